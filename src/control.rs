@@ -0,0 +1,157 @@
+// Copyright 2025 Johan Förberg
+// SPDX-License-Identifier: MIT
+
+use chrono::DateTime;
+use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
+use std::time::UNIX_EPOCH;
+
+use crate::repo::*;
+
+/// Name of the control directory in the root of the mount
+pub const CONTROL_DIR_NAME: &str = ".seafuse";
+
+/// Inode of the control directory. Inodes up to `FIRST_DYNAMIC_INO` are reserved for the control
+/// directory and its files.
+pub(crate) const CONTROL_DIR_INO: u64 = FUSE_ROOT_ID + 1;
+
+/// First inode number handed out for objects in the library
+pub(crate) const FIRST_DYNAMIC_INO: u64 = 16;
+
+/// Files in the control directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlFile {
+    /// The commit being served
+    Commit,
+
+    /// Where the library is stored, and its newest commit
+    Library,
+
+    /// Cache statistics
+    Stats,
+
+    /// Takes commands which change what is being served
+    Ctl,
+}
+
+pub(crate) const CONTROL_FILES: [ControlFile; 4] = [
+    ControlFile::Commit,
+    ControlFile::Library,
+    ControlFile::Stats,
+    ControlFile::Ctl,
+];
+
+/// Help text read from `ctl`
+const CTL_USAGE: &str = "\
+Write one of these commands to this file:
+
+refresh                  Switch to the newest commit of the library
+checkout <commit id>     Switch to the given commit
+checkout <time>          Switch to the newest commit made no later than the given time, in seconds
+                         since the epoch or in RFC 3339 format
+";
+
+impl ControlFile {
+    pub fn name(self) -> &'static str {
+        match self {
+            ControlFile::Commit => "commit.json",
+            ControlFile::Library => "library.json",
+            ControlFile::Stats => "stats",
+            ControlFile::Ctl => "ctl",
+        }
+    }
+
+    pub(crate) fn ino(self) -> u64 {
+        CONTROL_DIR_INO + 1 + self as u64
+    }
+
+    pub(crate) fn by_ino(ino: u64) -> Option<ControlFile> {
+        CONTROL_FILES.into_iter().find(|f| f.ino() == ino)
+    }
+
+    pub(crate) fn by_name(name: &str) -> Option<ControlFile> {
+        CONTROL_FILES.into_iter().find(|f| f.name() == name)
+    }
+
+    /// Only `ctl` can be written to
+    pub(crate) fn writable(self) -> bool {
+        self == ControlFile::Ctl
+    }
+
+    /// Attributes of the file. Contents are generated when the file is opened, so the size is
+    /// not known up front and reported as zero, like the files in /proc.
+    pub(crate) fn attr(self) -> FileAttr {
+        FileAttr {
+            perm: if self.writable() { 0o644 } else { 0o444 },
+            kind: FileType::RegularFile,
+            ..control_dir_attr(self.ino())
+        }
+    }
+}
+
+pub(crate) fn control_dir_attr(ino: u64) -> FileAttr {
+    FileAttr {
+        ino,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::Directory,
+        perm: 0o555,
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
+
+/// Command written to `ctl`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Switch to the newest commit
+    Refresh,
+
+    /// Switch to the commit with the given id
+    CheckoutId(Sha1),
+
+    /// Switch to the newest commit made no later than the given time
+    CheckoutTime(u64),
+}
+
+impl ControlCommand {
+    pub fn parse(line: &str) -> Result<ControlCommand, String> {
+        let mut words = line.split_whitespace();
+        let cmd = match (words.next(), words.next()) {
+            (Some("refresh"), None) => ControlCommand::Refresh,
+            (Some("checkout"), Some(arg)) => parse_checkout(arg)?,
+            _ => return Err(format!("Invalid command {line:?}")),
+        };
+
+        match words.next() {
+            None => Ok(cmd),
+            Some(_) => Err(format!("Invalid command {line:?}")),
+        }
+    }
+}
+
+fn parse_checkout(arg: &str) -> Result<ControlCommand, String> {
+    if let Some(id) = Sha1::parse(arg).filter(|_| arg.len() == 40) {
+        return Ok(ControlCommand::CheckoutId(id));
+    }
+
+    if let Ok(t) = arg.parse::<u64>() {
+        return Ok(ControlCommand::CheckoutTime(t));
+    }
+
+    match DateTime::parse_from_rfc3339(arg) {
+        Ok(t) if t.timestamp() >= 0 => Ok(ControlCommand::CheckoutTime(t.timestamp() as u64)),
+        _ => Err(format!("Not a commit id or time: {arg:?}")),
+    }
+}
+
+pub(crate) fn ctl_usage() -> Vec<u8> {
+    CTL_USAGE.as_bytes().to_vec()
+}
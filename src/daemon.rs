@@ -0,0 +1,102 @@
+// Copyright 2025 Johan Förberg
+// SPDX-License-Identifier: MIT
+
+use log::{debug, error};
+use nix::unistd::{dup2, fork, pipe, setsid, ForkResult};
+use seafuse::SeafError;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// The process serving a mount. Takes care of the pidfile, and when running in the background, of
+/// letting the parent process know once the filesystem is mounted.
+pub struct Daemon {
+    pidfile: Option<PathBuf>,
+
+    /// Pipe to the parent waiting for the mount, if we forked
+    parent: Option<fs::File>,
+
+    /// Whether the pidfile has been written, and should be removed on exit
+    pidfile_written: bool,
+}
+
+impl Daemon {
+    pub fn foreground(pidfile: Option<PathBuf>) -> Daemon {
+        Daemon {
+            pidfile,
+            parent: None,
+            pidfile_written: false,
+        }
+    }
+
+    /// Fork into the background. Only the child returns. The parent waits until the child calls
+    /// `ready`, and exits with an error if the child dies first. Must be called before any threads
+    /// are started.
+    pub fn background(pidfile: Option<PathBuf>) -> Daemon {
+        let (r, w) = pipe().expect("Failed to create pipe");
+
+        // SAFETY: The process is still single-threaded at this point
+        match unsafe { fork() }.expect("Failed to fork") {
+            ForkResult::Parent { .. } => {
+                drop(w);
+                let mut buf = [0];
+                match fs::File::from(r).read(&mut buf) {
+                    Ok(1) => process::exit(0),
+                    _ => process::exit(1),
+                }
+            }
+            ForkResult::Child => {
+                drop(r);
+                setsid().expect("Failed to start new session");
+
+                Daemon {
+                    pidfile,
+                    parent: Some(fs::File::from(w)),
+                    pidfile_written: false,
+                }
+            }
+        }
+    }
+
+    /// Report that the filesystem is mounted. Writes the pidfile, and when running in the
+    /// background, detaches from the terminal and lets the parent exit.
+    pub fn ready(&mut self) -> Result<(), SeafError> {
+        if let Some(path) = &self.pidfile {
+            fs::write(path, format!("{}\n", process::id()))
+                .map_err(|e| SeafError::IO(path.clone(), e))?;
+            self.pidfile_written = true;
+        }
+
+        if let Some(mut parent) = self.parent.take() {
+            let null = Path::new("/dev/null");
+            || -> io::Result<()> {
+                let f = fs::OpenOptions::new().read(true).write(true).open(null)?;
+                for fd in 0..=2 {
+                    dup2(f.as_raw_fd(), fd)?;
+                }
+                Ok(())
+            }()
+            .map_err(|e| SeafError::IO(null.into(), e))?;
+
+            // Only fails if the parent is gone, in which case nobody is waiting anyway
+            if let Err(e) = parent.write_all(&[0]) {
+                debug!("Failed to notify parent process: {e}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let (true, Some(path)) = (self.pidfile_written, &self.pidfile) {
+            debug!("Removing pidfile {path:?}");
+            if let Err(e) = fs::remove_file(path) {
+                error!("Failed to remove pidfile {path:?}: {e}");
+            }
+        }
+    }
+}
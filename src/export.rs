@@ -0,0 +1,253 @@
+// Copyright 2025 Johan Förberg
+// SPDX-License-Identifier: MIT
+
+use chrono::Local;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::debug;
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::{ExtendedFileOptions, FileOptions, StreamWriter};
+use zip::{CompressionMethod, ZipWriter};
+
+use seafuse::*;
+
+use crate::extract::{copy_error, match_prefix, PrefixMatch};
+
+/// Options for how a library is exported
+#[derive(Debug, Clone, clap::Args)]
+pub struct ExportArgs {
+    /// Format of the archive
+    #[arg(short = 'f', long, value_enum, default_value_t = ArchiveFormat::Tar)]
+    format: ArchiveFormat,
+
+    /// File to write the archive to, or - for standard output
+    #[arg(short = 'o', long)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    Tar,
+
+    /// Tar compressed with gzip
+    #[value(name = "tar.gz")]
+    TarGz,
+
+    Zip,
+}
+
+type Output = BufWriter<Box<dyn Write>>;
+
+/// Header id of the zip extra field with Unix times, and the flag for the modification time
+const EXTENDED_TIMESTAMP_ID: u16 = 0x5455;
+const EXTENDED_TIMESTAMP_MTIME: u8 = 1;
+
+/// An archive being written, one entry at a time
+enum Archive {
+    Tar(tar::Builder<Output>),
+    TarGz(tar::Builder<GzEncoder<Output>>),
+    Zip(ZipWriter<StreamWriter<Output>>),
+}
+
+/// Write the files and directories of `lib` below `prefix` to an archive. Files are read straight
+/// into the archive, so nothing is written anywhere else.
+pub fn do_export(lib: &Library, prefix: Option<&Path>, args: &ExportArgs) -> Result<(), SeafError> {
+    let to_stdout = args.output == Path::new("-");
+    let (out, output): (Box<dyn Write>, PathBuf) = match to_stdout {
+        true => (Box::new(io::stdout().lock()), "standard output".into()),
+        false => {
+            let f = fs::File::create(&args.output)
+                .map_err(|e| SeafError::IO(args.output.clone(), e))?;
+            (Box::new(f), args.output.clone())
+        }
+    };
+
+    let mut archive = Archive::new(args.format, BufWriter::new(out));
+    let r = export_entries(lib, prefix, &mut archive, &output).and_then(|counts| {
+        archive
+            .finish()
+            .map_err(|e| SeafError::IO(output.clone(), e))?;
+        match (counts, prefix) {
+            ((0, 0), Some(prefix)) => Err(SeafError::NotFound(format!("{}", prefix.display()))),
+            _ => Ok(counts),
+        }
+    });
+
+    // Leave no broken archive behind
+    if r.is_err() && !to_stdout {
+        if let Err(e) = fs::remove_file(&output) {
+            debug!("Failed to remove {}: {e}", output.display());
+        }
+    }
+    let (dirs, files) = r?;
+
+    // Standard output is taken by the archive
+    if !to_stdout {
+        println!("Exported {dirs} directories, {files} files");
+    }
+    Ok(())
+}
+
+fn export_entries(
+    lib: &Library,
+    prefix: Option<&Path>,
+    archive: &mut Archive,
+    output: &Path,
+) -> Result<(u64, u64), SeafError> {
+    let prefix = prefix.unwrap_or(Path::new(""));
+    let (mut dirs, mut files) = (0, 0);
+
+    let mut it = lib.fs_iterator();
+    while let Some(r) = it.next() {
+        let (p, de, fs) = r?;
+        let full_path = p.join(&de.name);
+
+        match match_prefix(prefix, &full_path) {
+            PrefixMatch::Yes => {}
+            PrefixMatch::No => {
+                if let FsJson::Dir(_) = fs {
+                    it.prune();
+                }
+                continue;
+            }
+            PrefixMatch::Continue => continue,
+        }
+
+        debug!("Exporting {}: {}", fs.type_name(), full_path.display());
+
+        match fs {
+            FsJson::Dir(_) => {
+                archive
+                    .add_dir(&full_path, &de)
+                    .map_err(|e| SeafError::IO(output.into(), e))?;
+                dirs += 1;
+            }
+            FsJson::File(f) => {
+                let mut r = lib.file_reader(&f)?;
+                archive
+                    .add_file(&full_path, &de, f.size, &mut r)
+                    .map_err(|e| copy_error(e, output).context(full_path.display().to_string()))?;
+                files += 1;
+            }
+        }
+    }
+
+    Ok((dirs, files))
+}
+
+impl Archive {
+    fn new(format: ArchiveFormat, out: Output) -> Archive {
+        match format {
+            ArchiveFormat::Tar => Archive::Tar(tar::Builder::new(out)),
+            ArchiveFormat::TarGz => Archive::TarGz(tar::Builder::new(GzEncoder::new(
+                out,
+                Compression::default(),
+            ))),
+            ArchiveFormat::Zip => Archive::Zip(ZipWriter::new_stream(out)),
+        }
+    }
+
+    fn add_dir(&mut self, path: &Path, de: &DirentJson) -> io::Result<()> {
+        let perm = permissions(de, 0o755);
+        match self {
+            Archive::Tar(b) => {
+                append_tar(b, path, de, perm, tar::EntryType::Directory, 0, io::empty())
+            }
+            Archive::TarGz(b) => {
+                append_tar(b, path, de, perm, tar::EntryType::Directory, 0, io::empty())
+            }
+            Archive::Zip(z) => {
+                // Not add_directory(), which leaves out the data descriptor a streamed entry is
+                // marked as having. Unzipping goes by the trailing slash anyway.
+                let options =
+                    zip_options(de, perm, 0)?.compression_method(CompressionMethod::Stored);
+                Ok(z.start_file(format!("{}/", zip_name(path)), options)?)
+            }
+        }
+    }
+
+    fn add_file(
+        &mut self,
+        path: &Path,
+        de: &DirentJson,
+        size: u64,
+        r: &mut impl Read,
+    ) -> io::Result<()> {
+        let perm = permissions(de, 0o644);
+        match self {
+            Archive::Tar(b) => append_tar(b, path, de, perm, tar::EntryType::Regular, size, r),
+            Archive::TarGz(b) => append_tar(b, path, de, perm, tar::EntryType::Regular, size, r),
+            Archive::Zip(z) => {
+                z.start_file(zip_name(path), zip_options(de, perm, size)?)?;
+                io::copy(r, z)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let mut out = match self {
+            Archive::Tar(b) => b.into_inner()?,
+            Archive::TarGz(b) => b.into_inner()?.finish()?,
+            Archive::Zip(z) => z.finish()?.into_inner(),
+        };
+        out.flush()
+    }
+}
+
+/// Permission bits recorded for an entry, or `default` if there are none
+fn permissions(de: &DirentJson, default: u32) -> u32 {
+    match de.mode & 0o7777 {
+        0 => default,
+        perm => perm,
+    }
+}
+
+fn append_tar<W: Write>(
+    b: &mut tar::Builder<W>,
+    path: &Path,
+    de: &DirentJson,
+    perm: u32,
+    ty: tar::EntryType,
+    size: u64,
+    data: impl Read,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(ty);
+    header.set_mode(perm);
+    header.set_mtime(de.mtime);
+    header.set_size(size);
+    b.append_data(&mut header, path, data)
+}
+
+fn zip_name(path: &Path) -> String {
+    path.display().to_string()
+}
+
+fn zip_options(
+    de: &DirentJson,
+    perm: u32,
+    size: u64,
+) -> zip::result::ZipResult<FileOptions<'static, ExtendedFileOptions>> {
+    // Zip times have no time zone, and are taken to be local
+    let mtime = chrono::DateTime::from_timestamp(de.mtime as i64, 0)
+        .map(|t| t.with_timezone(&Local).naive_local())
+        .and_then(|t| zip::DateTime::try_from(t).ok())
+        .unwrap_or_default();
+
+    let mut options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(perm)
+        .last_modified_time(mtime)
+        .large_file(size > u32::MAX as u64);
+
+    // That time is only to two seconds, so the exact one goes in an extended timestamp as well
+    let mut timestamp = vec![EXTENDED_TIMESTAMP_MTIME];
+    timestamp.extend(u32::try_from(de.mtime).unwrap_or(u32::MAX).to_le_bytes());
+    options.add_extra_data(EXTENDED_TIMESTAMP_ID, timestamp.into(), false)?;
+
+    Ok(options)
+}
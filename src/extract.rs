@@ -0,0 +1,916 @@
+// Copyright 2025 Johan Förberg
+// SPDX-License-Identifier: MIT
+
+use log::{debug, error, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use threadpool::ThreadPool;
+use walkdir::WalkDir;
+
+use seafuse::*;
+
+use crate::filter::{Filter, FilterArgs};
+
+/// Options for what is extracted and how
+#[derive(Debug, Clone, clap::Args)]
+pub struct ExtractArgs {
+    /// Only extract this file or directory
+    #[arg(short = 'p', long)]
+    prefix: Option<PathBuf>,
+
+    /// List what would be extracted without writing anything
+    #[arg(short = 'n', long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Leave modification times and permissions as they come out when writing the files,
+    /// instead of setting them as recorded in the library
+    #[arg(long, default_value_t = false)]
+    no_preserve: bool,
+
+    /// Carry on past files and directories which cannot be extracted, and fail at the end
+    #[arg(short = 'k', long, default_value_t = false)]
+    keep_going: bool,
+
+    /// Write the paths which could not be extracted, and why, to this file
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Format of the report
+    #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+    report_format: ReportFormat,
+
+    /// Number of files to write at once
+    #[arg(short = 'j', long, default_value_t = 1)]
+    jobs: usize,
+
+    /// Skip files which are already in the target with the size and modification time recorded
+    /// in the library
+    #[arg(short = 'u', long, default_value_t = false)]
+    update: bool,
+
+    /// Keep the content ids of extracted files in this file, and with --update skip files whose
+    /// id is unchanged instead of comparing modification times
+    #[arg(long)]
+    state: Option<PathBuf>,
+
+    /// Remove files and directories from the target which are not in the library
+    #[arg(long, default_value_t = false)]
+    delete: bool,
+
+    /// Carry on with an extraction which was interrupted, skipping the files its journal says
+    /// were written
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+
+    #[command(flatten)]
+    filter: FilterArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// One `path: cause` line per failure
+    Text,
+
+    /// A JSON object with the number of extracted entries and the failures
+    Json,
+}
+
+/// Suffix given to files which could only be partly written
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Number of files per worker which may wait to be written before the walk pauses
+const QUEUED_FILES_PER_JOB: usize = 16;
+
+/// Name of the journal kept in the target while extracting
+const JOURNAL_NAME: &str = ".seafuse-journal";
+
+/// Number of entries written to the journal between syncs, of the journal and of the files it
+/// records. Entries lost in a crash only mean that their files are written again when resuming.
+const JOURNAL_SYNC_INTERVAL: usize = 64;
+
+#[derive(Debug, PartialEq)]
+pub enum PrefixMatch {
+    Yes,
+    No,
+    Continue,
+}
+
+pub fn do_extract(lib: &Library, target: &Path, args: &ExtractArgs) -> Result<(), SeafError> {
+    fs::create_dir_all(target).map_err(|e| SeafError::IO(target.into(), e))?;
+
+    let state = match &args.state {
+        Some(path) => load_state(path)?,
+        None => State::new(),
+    };
+
+    let filter = args.filter.build()?;
+    let commit_id = lib.head_commit.commit_id;
+    let journal_path = target.join(JOURNAL_NAME);
+    let done = match args.resume {
+        true => read_journal(&journal_path, commit_id)?,
+        false => HashSet::new(),
+    };
+    let journal = match args.dry_run {
+        true => None,
+        false => Some(Journal::create(journal_path, commit_id, &done)?),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let mut ex = Extraction {
+        lib,
+        target,
+        args,
+        pool: (args.jobs > 1).then(|| ThreadPool::new(args.jobs)),
+        tx,
+        rx,
+        pending: 0,
+        dir_counter: 0,
+        file_counter: 0,
+        unchanged_counter: 0,
+        deleted_counter: 0,
+        resumed_counter: 0,
+        failures: vec![],
+        dir_times: vec![],
+        state,
+        filter,
+        seen: HashSet::new(),
+        pruned: HashSet::new(),
+        journal,
+        done,
+    };
+
+    let r = ex.run();
+
+    // Whatever happened, no worker may be left writing once we return
+    if let Some(pool) = &ex.pool {
+        pool.join();
+    }
+
+    // Also after a failure, so that the next run can skip what did get written
+    let saved = match &args.state {
+        Some(path) if !args.dry_run => save_state(path, &ex.state),
+        _ => Ok(()),
+    };
+    r?;
+    saved?;
+
+    // Only a complete extraction is done with its journal. With failures, resuming retries them.
+    if let Some(journal) = ex.journal.take() {
+        if ex.failures.is_empty() {
+            journal.remove()?;
+        }
+    }
+
+    print!(
+        "Extracted {} directories, {} files",
+        ex.dir_counter, ex.file_counter
+    );
+    if args.update {
+        print!(", {} unchanged", ex.unchanged_counter);
+    }
+    if args.delete {
+        print!(", deleted {}", ex.deleted_counter);
+    }
+    if args.resume {
+        print!(", {} already done", ex.resumed_counter);
+    }
+    match ex.failures.len() {
+        0 => println!(),
+        n => println!(", {n} failed"),
+    }
+    debug!("Fs cache: {:?}", lib.fs_cache_stats());
+    debug!("Block file cache: {:?}", lib.block_file_cache_stats());
+    debug!("Block cache: {:?}", lib.block_cache_stats());
+
+    if let Some(path) = &args.report {
+        let report = match args.report_format {
+            ReportFormat::Text => text_report(&ex.failures),
+            ReportFormat::Json => json_report(&ex.failures, ex.dir_counter, ex.file_counter),
+        };
+        fs::write(path, report).map_err(|e| SeafError::IO(path.clone(), e))?;
+    }
+
+    let count = ex.failures.len();
+    match ex.failures.into_iter().next() {
+        Some(first) => {
+            Err(first.context(format!("Failed to extract {count} entries; first failure")))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Outcome of writing one file, along with its path and id in the library
+type FileResult = (PathBuf, Sha1, Result<(), SeafError>);
+
+/// Sends the result of writing a file once dropped. A worker which panics still drops it, and
+/// so reports a failure instead of leaving the extraction waiting for a result forever.
+struct Reply {
+    tx: Sender<FileResult>,
+    full_path: PathBuf,
+    id: Sha1,
+    target_path: PathBuf,
+    result: Option<Result<(), SeafError>>,
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        let r = self.result.take().unwrap_or_else(|| {
+            let e = io::Error::other("Worker writing the file panicked");
+            Err(SeafError::IO(self.target_path.clone(), e))
+        });
+        // Only fails if the extraction has already been given up
+        let _ = self.tx.send((self.full_path.clone(), self.id, r));
+    }
+}
+
+/// Content ids of the files in the target as of the last extraction, by path
+type State = BTreeMap<String, Sha1>;
+
+/// State of an extraction. Directories are created while walking the library, so that they
+/// always exist before anything is written into them. Files are written by a pool of workers if
+/// there is more than one job, and right away otherwise.
+struct Extraction<'a> {
+    lib: &'a Library,
+    target: &'a Path,
+    args: &'a ExtractArgs,
+    pool: Option<ThreadPool>,
+
+    /// Results of files written by the workers
+    tx: Sender<FileResult>,
+    rx: Receiver<FileResult>,
+
+    /// Number of files queued whose results have not been received
+    pending: usize,
+
+    dir_counter: u64,
+    file_counter: u64,
+    unchanged_counter: u64,
+    deleted_counter: u64,
+    resumed_counter: u64,
+    failures: Vec<SeafError>,
+
+    /// Directory times are set once everything has been written, as creating entries in a
+    /// directory updates its modification time
+    dir_times: Vec<(PathBuf, u64)>,
+
+    state: State,
+
+    filter: Filter,
+
+    /// Paths found in the library, for --delete to keep
+    seen: HashSet<PathBuf>,
+
+    /// Directories left out by the filter, which --delete leaves alone as well
+    pruned: HashSet<PathBuf>,
+
+    journal: Option<Journal>,
+
+    /// Files written by the extraction being resumed
+    done: HashSet<PathBuf>,
+}
+
+impl Extraction<'_> {
+    fn run(&mut self) -> Result<(), SeafError> {
+        let prefix = self.args.prefix.as_deref().unwrap_or(Path::new(""));
+
+        let mut it = self.lib.fs_iterator();
+        while let Some(r) = it.next() {
+            let (p, de, fs) = match r {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.fail(e)?;
+                    continue;
+                }
+            };
+            let full_path = p.join(&de.name);
+            let target_path = self.target.join(&full_path);
+
+            match match_prefix(prefix, &full_path) {
+                PrefixMatch::Yes => {}
+                PrefixMatch::No => {
+                    if let FsJson::Dir(_) = fs {
+                        debug!("Pruning directory {full_path:?}");
+                        it.prune();
+                    }
+                    continue;
+                }
+                PrefixMatch::Continue => {
+                    debug!("Ignoring directory {full_path:?}");
+                    if self.args.delete {
+                        self.seen.insert(full_path);
+                    }
+                    continue;
+                }
+            }
+
+            if self.args.delete {
+                self.seen.insert(full_path.clone());
+            }
+
+            match &fs {
+                FsJson::Dir(_) if self.filter.excludes_dir(&full_path) => {
+                    debug!("Excluding directory {full_path:?}");
+                    it.prune();
+                    self.pruned.insert(full_path);
+                    continue;
+                }
+                FsJson::Dir(_) if !self.filter.includes_dir(&full_path) => {
+                    // Only created if something inside it is included
+                    if !self.args.no_preserve && !self.args.dry_run {
+                        self.dir_times.push((target_path, de.mtime));
+                    }
+                    continue;
+                }
+                FsJson::File(f) if !self.filter.includes_file(&full_path, f, &de) => {
+                    debug!("Excluding file {full_path:?}");
+                    continue;
+                }
+                _ => {}
+            }
+
+            if let FsJson::File(f) = &fs {
+                if self.is_done(f, &full_path, &target_path) {
+                    debug!("Already done: {}", target_path.display());
+                    self.state.insert(full_path.display().to_string(), de.id);
+                    self.resumed_counter += 1;
+                    continue;
+                }
+                if self.args.update && self.is_unchanged(f, &de, &full_path, &target_path) {
+                    debug!("Unchanged: {}", target_path.display());
+                    self.unchanged_counter += 1;
+                    continue;
+                }
+            }
+
+            debug!("Extracting {}: {}", fs.type_name(), target_path.display());
+
+            if self.args.dry_run {
+                continue;
+            }
+
+            match fs {
+                FsJson::Dir(_) => match extract_dir(&target_path, self.args) {
+                    Ok(()) => {
+                        if !self.args.no_preserve {
+                            self.dir_times.push((target_path, de.mtime));
+                        }
+                        self.dir_counter += 1;
+                    }
+                    Err(e) => self.fail(e.context(full_path.display().to_string()))?,
+                },
+                FsJson::File(f) => self.queue_file(f, de, full_path, target_path)?,
+            }
+        }
+
+        while self.pending > 0 {
+            self.wait_for_file()?;
+        }
+
+        if self.args.delete {
+            self.delete_stray(prefix)?;
+        }
+
+        for (path, mtime) in std::mem::take(&mut self.dir_times) {
+            match fs::File::open(&path).and_then(|d| d.set_modified(to_system_time(mtime))) {
+                Ok(()) => {}
+                // Directories without any included files are never created
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => self.fail(SeafError::IO(path, e))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn queue_file(
+        &mut self,
+        f: FileJson,
+        de: DirentJson,
+        full_path: PathBuf,
+        target_path: PathBuf,
+    ) -> Result<(), SeafError> {
+        let lib = self.lib.clone();
+        let args = self.args.clone();
+        let reply = Reply {
+            tx: self.tx.clone(),
+            full_path,
+            id: de.id,
+            target_path: target_path.clone(),
+            result: None,
+        };
+        let job = move || {
+            let mut reply = reply;
+            reply.result = Some(extract_file(&lib, &f, &de, &target_path, &args));
+        };
+
+        let Some(pool) = &self.pool else {
+            job();
+            self.pending += 1;
+            return self.wait_for_file();
+        };
+
+        pool.execute(job);
+        self.pending += 1;
+
+        // Keep the walk from getting too far ahead of the workers
+        while self.pending > QUEUED_FILES_PER_JOB * self.args.jobs {
+            self.wait_for_file()?;
+        }
+
+        while let Ok(res) = self.rx.try_recv() {
+            self.file_done(res)?;
+        }
+
+        Ok(())
+    }
+
+    fn wait_for_file(&mut self) -> Result<(), SeafError> {
+        // Cannot fail, as we hold a sender ourselves
+        let res = self.rx.recv().unwrap();
+        self.file_done(res)
+    }
+
+    fn file_done(&mut self, (full_path, id, r): FileResult) -> Result<(), SeafError> {
+        self.pending -= 1;
+        let key = full_path.display().to_string();
+        match r {
+            Ok(()) => {
+                self.file_counter += 1;
+                self.state.insert(key, id);
+                match &mut self.journal {
+                    Some(journal) => journal.record(&full_path, self.target.join(&full_path)),
+                    None => Ok(()),
+                }
+            }
+            Err(e) => {
+                // Whatever is left in the target is not that file any more
+                self.state.remove(&key);
+                self.fail(e.context(key))
+            }
+        }
+    }
+
+    /// Whether the journal of the extraction being resumed has `full_path` as written. The size is
+    /// checked too, in case the file did not make it to disk before the journal entry did.
+    fn is_done(&self, f: &FileJson, full_path: &Path, target_path: &Path) -> bool {
+        self.done.contains(full_path)
+            && fs::symlink_metadata(target_path).is_ok_and(|md| md.is_file() && md.len() == f.size)
+    }
+
+    /// Whether the file at `target_path` already has the content of `f`. That is assumed if its
+    /// size and modification time match, or with a state file, if its size and content id do.
+    fn is_unchanged(
+        &self,
+        f: &FileJson,
+        de: &DirentJson,
+        full_path: &Path,
+        target_path: &Path,
+    ) -> bool {
+        let Ok(md) = fs::symlink_metadata(target_path) else {
+            return false;
+        };
+        if !md.is_file() || md.len() != f.size {
+            return false;
+        }
+
+        if self.args.state.is_none() {
+            return md.mtime() as u64 == de.mtime;
+        }
+        if self.state.get(&full_path.display().to_string()) != Some(&de.id) {
+            return false;
+        }
+
+        // Only the times may differ, which is cheap to put right
+        if !self.args.no_preserve && !self.args.dry_run && md.mtime() as u64 != de.mtime {
+            let r = fs::File::open(target_path).and_then(|w| preserve_file_attrs(&w, de));
+            if let Err(e) = r {
+                error!("Failed to update times of {}: {e}", target_path.display());
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Remove whatever is in the target below `prefix` but was not found in the library. Nothing
+    /// is removed if any part of the library could not be read, as it might just be missing from
+    /// the walk.
+    fn delete_stray(&mut self, prefix: &Path) -> Result<(), SeafError> {
+        if !self.failures.is_empty() {
+            warn!("Not deleting anything, as some entries could not be extracted");
+            return Ok(());
+        }
+
+        let own_files: Vec<PathBuf> = [&self.args.state, &self.args.report]
+            .into_iter()
+            .flatten()
+            .filter_map(|p| p.canonicalize().ok())
+            .collect();
+
+        let mut it = WalkDir::new(self.target).min_depth(1).into_iter();
+        while let Some(r) = it.next() {
+            let e = match r {
+                Ok(e) => e,
+                Err(e) => {
+                    self.fail(e.into())?;
+                    continue;
+                }
+            };
+            let path = e.path().strip_prefix(self.target).unwrap();
+            let is_dir = e.file_type().is_dir();
+
+            if self.pruned.contains(path) {
+                if is_dir {
+                    it.skip_current_dir();
+                }
+                continue;
+            }
+            if self.seen.contains(path) || path == Path::new(JOURNAL_NAME) {
+                continue;
+            }
+            match match_prefix(prefix, path) {
+                PrefixMatch::Yes => {}
+                PrefixMatch::No => {
+                    if is_dir {
+                        it.skip_current_dir();
+                    }
+                    continue;
+                }
+                PrefixMatch::Continue => continue,
+            }
+            if e.path()
+                .canonicalize()
+                .is_ok_and(|p| own_files.contains(&p))
+            {
+                continue;
+            }
+
+            debug!("Deleting {}", e.path().display());
+            if is_dir {
+                it.skip_current_dir();
+            }
+            if self.args.dry_run {
+                continue;
+            }
+
+            match remove_entry(e.path(), is_dir) {
+                Ok(()) => {
+                    self.deleted_counter += 1;
+                    self.state.retain(|k, _| !Path::new(k).starts_with(path));
+                }
+                Err(e) => self.fail(e)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// With --keep-going, errors are logged and collected rather than ending the extraction
+    fn fail(&mut self, e: SeafError) -> Result<(), SeafError> {
+        if !self.args.keep_going {
+            return Err(e);
+        }
+        error!("{}", e.report());
+        self.failures.push(e);
+        Ok(())
+    }
+}
+
+fn extract_dir(target_path: &Path, args: &ExtractArgs) -> Result<(), SeafError> {
+    if args.delete && fs::symlink_metadata(target_path).is_ok_and(|md| !md.is_dir()) {
+        remove_entry(target_path, false)?;
+    }
+
+    match fs::create_dir_all(target_path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(SeafError::IO(target_path.into(), e)),
+    }
+}
+
+/// Write the file `f` to `target_path`. If it cannot be read to the end, whatever was written is
+/// kept with `PARTIAL_SUFFIX` added to its name.
+fn extract_file(
+    lib: &Library,
+    f: &FileJson,
+    de: &DirentJson,
+    target_path: &Path,
+    args: &ExtractArgs,
+) -> Result<(), SeafError> {
+    if let Some(parent) = target_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| SeafError::IO(parent.into(), e))?;
+    }
+    if args.delete && fs::symlink_metadata(target_path).is_ok_and(|md| md.is_dir()) {
+        remove_entry(target_path, true)?;
+    }
+
+    let mut r = lib.file_reader(f)?;
+    let mut w = fs::File::create(target_path).map_err(|e| SeafError::IO(target_path.into(), e))?;
+
+    if let Err(e) = io::copy(&mut r, &mut w) {
+        let mut partial = target_path.as_os_str().to_owned();
+        partial.push(PARTIAL_SUFFIX);
+        if let Err(e) = fs::rename(target_path, &partial) {
+            error!("Failed to rename partial file {target_path:?}: {e}");
+        }
+        return Err(copy_error(e, target_path));
+    }
+
+    if !args.no_preserve {
+        preserve_file_attrs(&w, de).map_err(|e| SeafError::IO(target_path.into(), e))?;
+    }
+
+    Ok(())
+}
+
+/// Record of the files written so far. The first line has the commit being extracted, and each
+/// following line the path of a written file as a JSON string. Files are synced in batches, right
+/// before the journal is, so that the journal never gets to disk ahead of the files it records.
+struct Journal {
+    path: PathBuf,
+    file: fs::File,
+
+    /// Files recorded since the last sync
+    unsynced: Vec<PathBuf>,
+}
+
+impl Journal {
+    /// Start the journal at `path`, with the files in `done` already recorded
+    fn create(
+        path: PathBuf,
+        commit_id: Sha1,
+        done: &HashSet<PathBuf>,
+    ) -> Result<Journal, SeafError> {
+        let mut contents = format!("commit {commit_id}\n");
+        for p in done {
+            contents += &journal_line(p);
+        }
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let file = fs::File::create(&tmp)
+            .and_then(|mut f| f.write_all(contents.as_bytes()).and_then(|()| f.sync_all()))
+            .and_then(|()| fs::rename(&tmp, &path))
+            .and_then(|()| fs::OpenOptions::new().append(true).open(&path))
+            .map_err(|e| SeafError::IO(path.clone(), e))?;
+
+        Ok(Journal {
+            path,
+            file,
+            unsynced: Vec::new(),
+        })
+    }
+
+    /// Record the file `full_path`, written to `target_path`
+    fn record(&mut self, full_path: &Path, target_path: PathBuf) -> Result<(), SeafError> {
+        // One write per line, so that an interruption can at most leave the last line torn
+        self.file
+            .write_all(journal_line(full_path).as_bytes())
+            .map_err(|e| SeafError::IO(self.path.clone(), e))?;
+
+        self.unsynced.push(target_path);
+        if self.unsynced.len() >= JOURNAL_SYNC_INTERVAL {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), SeafError> {
+        for path in self.unsynced.drain(..) {
+            fs::File::open(&path)
+                .and_then(|f| f.sync_all())
+                .map_err(|e| SeafError::IO(path, e))?;
+        }
+        self.file
+            .sync_data()
+            .map_err(|e| SeafError::IO(self.path.clone(), e))
+    }
+
+    fn remove(mut self) -> Result<(), SeafError> {
+        self.unsynced.clear();
+        fs::remove_file(&self.path).map_err(|e| SeafError::IO(self.path.clone(), e))
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        // A journal left behind is there to be resumed from, so what it has should be kept
+        if !self.unsynced.is_empty() {
+            if let Err(e) = self.sync() {
+                error!("Failed to sync the journal: {}", e.report());
+            }
+        }
+    }
+}
+
+fn journal_line(full_path: &Path) -> String {
+    let mut line = serde_json::to_string(&full_path.display().to_string()).unwrap();
+    line.push('\n');
+    line
+}
+
+/// Read the paths recorded in the journal at `path`, which must be for `commit_id`. Without a
+/// journal there is nothing to resume, and everything is extracted.
+fn read_journal(path: &Path, commit_id: Sha1) -> Result<HashSet<PathBuf>, SeafError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            warn!(
+                "No journal at {}, starting from the beginning",
+                path.display()
+            );
+            return Ok(HashSet::new());
+        }
+        Err(e) => return Err(SeafError::IO(path.into(), e)),
+    };
+
+    let mut lines = contents.lines();
+    let journal_commit = lines
+        .next()
+        .and_then(|l| l.strip_prefix("commit "))
+        .and_then(Sha1::parse);
+    let invalid =
+        |msg: String| SeafError::IO(path.into(), io::Error::new(ErrorKind::InvalidData, msg));
+    match journal_commit {
+        Some(id) if id == commit_id => {}
+        Some(id) => {
+            return Err(invalid(format!(
+                "Journal is for commit {id}, but the library is at {commit_id}"
+            )))
+        }
+        None => return Err(invalid("Journal has no commit id".into())),
+    }
+
+    // A torn last line does not parse, and is left out
+    Ok(lines
+        .filter_map(|l| serde_json::from_str::<String>(l).ok())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Remove a file or a directory with everything in it
+fn remove_entry(path: &Path, is_dir: bool) -> Result<(), SeafError> {
+    let r = if is_dir {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    r.map_err(|e| SeafError::IO(path.into(), e))
+}
+
+/// Read the state file at `path`, which need not exist yet
+fn load_state(path: &Path) -> Result<State, SeafError> {
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| SeafError::ParseJson(path.into(), e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(State::new()),
+        Err(e) => Err(SeafError::IO(path.into(), e)),
+    }
+}
+
+/// Replace the state file at `path`, so that an interrupted write leaves the old one in place
+fn save_state(path: &Path, state: &State) -> Result<(), SeafError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let data = serde_json::to_vec_pretty(state).unwrap();
+    fs::write(&tmp, data)
+        .and_then(|()| fs::rename(&tmp, path))
+        .map_err(|e| SeafError::IO(path.into(), e))
+}
+
+/// Split a failure into the path it happened at, if known, and its cause
+fn failure_parts(e: &SeafError) -> (String, String) {
+    match e {
+        SeafError::Context(path, cause) => (path.clone(), cause.report()),
+        e => (String::new(), e.report()),
+    }
+}
+
+fn text_report(failures: &[SeafError]) -> String {
+    failures
+        .iter()
+        .map(|e| match failure_parts(e) {
+            (path, cause) if path.is_empty() => format!("{cause}\n"),
+            (path, cause) => format!("{path}: {cause}\n"),
+        })
+        .collect()
+}
+
+fn json_report(failures: &[SeafError], dirs: u64, files: u64) -> String {
+    let failures: Vec<_> = failures
+        .iter()
+        .map(|e| {
+            let (path, cause) = failure_parts(e);
+            serde_json::json!({"path": path, "error": cause, "errno": e.errno()})
+        })
+        .collect();
+
+    let mut report = serde_json::to_string_pretty(&serde_json::json!({
+        "dirs": dirs,
+        "files": files,
+        "failures": failures,
+    }))
+    .unwrap();
+    report.push('\n');
+    report
+}
+
+/// Give an extracted file the permissions and modification time recorded for it. Entries without
+/// permission bits keep the ones they were created with, so that they stay readable.
+fn preserve_file_attrs(f: &fs::File, de: &DirentJson) -> io::Result<()> {
+    let perm = de.mode & 0o777;
+    if perm != 0 {
+        f.set_permissions(fs::Permissions::from_mode(perm))?;
+    }
+
+    f.set_modified(to_system_time(de.mtime))
+}
+
+fn to_system_time(t: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(t)
+}
+
+/// Turn an error from copying out of a `FileReader` back into the library error behind it, if
+/// reading rather than writing failed
+pub fn copy_error(e: io::Error, target: &Path) -> SeafError {
+    if !e.get_ref().is_some_and(|inner| inner.is::<SeafError>()) {
+        return SeafError::IO(target.into(), e);
+    }
+
+    *e.into_inner().unwrap().downcast::<SeafError>().unwrap()
+}
+
+pub fn match_prefix(pref: &Path, path: &Path) -> PrefixMatch {
+    let ret = if pref.as_os_str().is_empty() || path.starts_with(pref) {
+        PrefixMatch::Yes
+    } else if pref.starts_with(path) {
+        PrefixMatch::Continue
+    } else {
+        PrefixMatch::No
+    };
+
+    ret
+}
+
+#[test]
+fn test_reply_after_panic() {
+    let (tx, rx) = mpsc::channel();
+    let reply = Reply {
+        tx: tx.clone(),
+        full_path: "a".into(),
+        id: Sha1::default(),
+        target_path: "t/a".into(),
+        result: None,
+    };
+    let worker = std::thread::spawn(move || {
+        let _reply = reply;
+        panic!("Oops");
+    });
+    assert!(worker.join().is_err());
+
+    let (full_path, _, r) = rx.recv().unwrap();
+    assert_eq!(full_path, Path::new("a"));
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_prefix_examples() {
+    assert_eq!(
+        match_prefix(Path::new(""), Path::new("test/test2")),
+        PrefixMatch::Yes
+    );
+    assert_eq!(match_prefix(Path::new(""), Path::new("")), PrefixMatch::Yes);
+    assert_eq!(
+        match_prefix(Path::new("test"), Path::new("")),
+        PrefixMatch::Continue
+    );
+
+    assert_eq!(
+        match_prefix(Path::new("test"), Path::new("test/test2")),
+        PrefixMatch::Yes
+    );
+    assert_eq!(
+        match_prefix(Path::new("test2"), Path::new("test")),
+        PrefixMatch::No
+    );
+    assert_eq!(
+        match_prefix(Path::new("test2"), Path::new("test/test2")),
+        PrefixMatch::No
+    );
+    assert_eq!(
+        match_prefix(Path::new("test/test2"), Path::new("test")),
+        PrefixMatch::Continue
+    );
+    assert_eq!(
+        match_prefix(Path::new("test/test2"), Path::new("test/test2")),
+        PrefixMatch::Yes
+    );
+    assert_eq!(
+        match_prefix(Path::new("test/test2"), Path::new("test/test")),
+        PrefixMatch::No
+    );
+}
@@ -0,0 +1,240 @@
+// Copyright 2025 Johan Förberg
+// SPDX-License-Identifier: MIT
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use seafuse::*;
+
+/// Options for which files are extracted
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct FilterArgs {
+    /// Only extract files matching this gitignore-style pattern. May be repeated.
+    #[arg(long, value_name = "PATTERN", value_parser = parse_pattern)]
+    include: Vec<String>,
+
+    /// Leave out files and directories matching this gitignore-style pattern, such as
+    /// `**/*.psd`. A pattern starting with `!` brings back what an earlier one left out. May be
+    /// repeated.
+    #[arg(long, value_name = "PATTERN", value_parser = parse_pattern)]
+    exclude: Vec<String>,
+
+    /// Read patterns to leave out from this file, one per line as in a .gitignore file. They
+    /// come before any --exclude patterns.
+    #[arg(long, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+
+    /// Leave out files larger than this, in bytes or with a K, M, G or T suffix
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Only extract files modified after this date or time, such as 2025-01-31 or
+    /// 2025-01-31T12:00:00 in local time, or an RFC 3339 time
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    newer_than: Option<u64>,
+}
+
+impl FilterArgs {
+    pub fn build(&self) -> Result<Filter, SeafError> {
+        let mut exclude = GitignoreBuilder::new("");
+        for path in &self.exclude_from {
+            let contents = fs::read_to_string(path).map_err(|e| SeafError::IO(path.clone(), e))?;
+            for line in contents.lines() {
+                exclude
+                    .add_line(Some(path.clone()), line)
+                    .map_err(|e| invalid_pattern(path, e))?;
+            }
+        }
+        for pattern in &self.exclude {
+            // Checked when parsing the arguments
+            exclude.add_line(None, pattern).unwrap();
+        }
+
+        let include = match self.include.is_empty() {
+            true => None,
+            false => {
+                let mut include = GitignoreBuilder::new("");
+                for pattern in &self.include {
+                    include.add_line(None, pattern).unwrap();
+                }
+                Some(include.build().unwrap())
+            }
+        };
+
+        Ok(Filter {
+            exclude: exclude.build().unwrap(),
+            include,
+            max_size: self.max_size,
+            newer_than: self.newer_than,
+        })
+    }
+}
+
+/// Decides which entries are extracted, by their path relative to the root of the library
+pub struct Filter {
+    exclude: Gitignore,
+    include: Option<Gitignore>,
+    max_size: Option<u64>,
+    newer_than: Option<u64>,
+}
+
+impl Filter {
+    /// Whether the directory at `path` is left out, along with everything below it
+    pub fn excludes_dir(&self, path: &Path) -> bool {
+        self.exclude.matched(path, true).is_ignore()
+    }
+
+    /// Whether the directory at `path` is extracted in its own right. If not, it is still walked,
+    /// but only created to hold files which are included.
+    pub fn includes_dir(&self, path: &Path) -> bool {
+        match &self.include {
+            Some(include) => include.matched(path, true).is_ignore(),
+            None => true,
+        }
+    }
+
+    pub fn includes_file(&self, path: &Path, f: &FileJson, de: &DirentJson) -> bool {
+        if self.exclude.matched(path, false).is_ignore() {
+            return false;
+        }
+        if let Some(include) = &self.include {
+            if !include.matched_path_or_any_parents(path, false).is_ignore() {
+                return false;
+            }
+        }
+
+        self.max_size.is_none_or(|max| f.size <= max)
+            && self.newer_than.is_none_or(|t| de.mtime > t)
+    }
+}
+
+fn invalid_pattern(path: &Path, e: ignore::Error) -> SeafError {
+    SeafError::IO(path.into(), io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn parse_pattern(s: &str) -> Result<String, String> {
+    GitignoreBuilder::new("")
+        .add_line(None, s)
+        .map(|_| s.to_owned())
+        .map_err(|e| e.to_string())
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("Invalid size {s:?}"))
+}
+
+fn parse_time(s: &str) -> Result<u64, String> {
+    let t = match DateTime::parse_from_rfc3339(s) {
+        Ok(t) => t.timestamp(),
+        Err(_) => {
+            let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+                .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S"))
+                .or_else(|_| {
+                    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                        .map(|d| d.and_hms_opt(0, 0, 0).unwrap())
+                })
+                .map_err(|_| format!("Invalid time {s:?}"))?;
+            Local
+                .from_local_datetime(&naive)
+                .earliest()
+                .ok_or_else(|| format!("No such local time {s:?}"))?
+                .timestamp()
+        }
+    };
+
+    u64::try_from(t).map_err(|_| format!("Time {s:?} is before 1970"))
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("0"), Ok(0));
+    assert_eq!(parse_size("1500"), Ok(1500));
+    assert_eq!(parse_size("10k"), Ok(10 << 10));
+    assert_eq!(parse_size("3M"), Ok(3 << 20));
+    assert_eq!(parse_size("2G"), Ok(2 << 30));
+    assert!(parse_size("").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("1.5M").is_err());
+    assert!(parse_size("100000000T").is_err());
+}
+
+#[test]
+fn test_parse_time() {
+    assert_eq!(parse_time("2025-02-01T12:00:00Z"), Ok(1738411200));
+    assert_eq!(parse_time("2025-02-01T13:00:00+01:00"), Ok(1738411200));
+    let midnight = parse_time("2025-02-01").unwrap();
+    assert_eq!(parse_time("2025-02-01T12:00:00"), Ok(midnight + 12 * 3600));
+    assert_eq!(parse_time("2025-02-01 12:00:00"), Ok(midnight + 12 * 3600));
+    assert!(parse_time("yesterday").is_err());
+    assert!(parse_time("1969-01-01T00:00:00Z").is_err());
+}
+
+#[test]
+fn test_filter_patterns() {
+    let f = FileJson {
+        block_ids: vec![],
+        size: 1000,
+        ty: 1,
+        version: 1,
+    };
+    let de = DirentJson {
+        id: Sha1::parse("0000000000000000000000000000000000000000").unwrap(),
+        mode: 0o100644,
+        mtime: 1738411200,
+        name: String::new(),
+        modifier: None,
+    };
+
+    let args = FilterArgs {
+        exclude: vec!["**/*.psd".into(), "archive/".into(), "!keep.psd".into()],
+        ..FilterArgs::default()
+    };
+    let filter = args.build().unwrap();
+    assert!(filter.excludes_dir(Path::new("archive")));
+    assert!(filter.excludes_dir(Path::new("a/b/archive")));
+    assert!(!filter.excludes_dir(Path::new("archived")));
+    assert!(filter.includes_dir(Path::new("archived")));
+    assert!(!filter.includes_file(Path::new("a/b/image.psd"), &f, &de));
+    assert!(filter.includes_file(Path::new("a/b/keep.psd"), &f, &de));
+    assert!(filter.includes_file(Path::new("a/b/image.png"), &f, &de));
+
+    let args = FilterArgs {
+        include: vec!["*.md".into(), "docs/".into()],
+        max_size: Some(1000),
+        newer_than: Some(1738411199),
+        ..FilterArgs::default()
+    };
+    let filter = args.build().unwrap();
+    assert!(filter.includes_file(Path::new("a/README.md"), &f, &de));
+    assert!(filter.includes_file(Path::new("docs/a/b.txt"), &f, &de));
+    assert!(!filter.includes_file(Path::new("a/b.txt"), &f, &de));
+    assert!(filter.includes_dir(Path::new("docs")));
+    assert!(!filter.includes_dir(Path::new("src")));
+
+    let big = FileJson {
+        size: 1001,
+        ..f.clone()
+    };
+    assert!(!filter.includes_file(Path::new("a/README.md"), &big, &de));
+    let old = DirentJson {
+        mtime: 1738411199,
+        ..de.clone()
+    };
+    assert!(!filter.includes_file(Path::new("a/README.md"), &f, &old));
+}
@@ -4,61 +4,247 @@
 use bimap::BiMap;
 use core::time::Duration;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, Request, FUSE_ROOT_ID,
+    consts, fuse_forget_one, FileAttr, FileType, Filesystem, KernelConfig, Notifier, ReplyAttr,
+    ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen,
+    ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID,
 };
-use libc::{c_int, EBADF, EINVAL, EIO, ENOENT, ENOTDIR};
-use log::{debug, error};
+use libc::{
+    c_int, EACCES, EBADF, EINVAL, EISDIR, ENODATA, ENOENT, ENOTDIR, ERANGE, EROFS, ESTALE,
+    O_ACCMODE, O_RDONLY,
+};
+use log::{debug, error, info};
 use std::cmp::min;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
-use std::io::{Read, Seek, SeekFrom};
-use std::time::UNIX_EPOCH;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use threadpool::ThreadPool;
 
+use crate::control::*;
 use crate::repo::*;
 
-const INF_TTL: Duration = Duration::new(1_000_000_000, 0);
+/// Time the kernel may cache entries and attributes for by default. Everything but the root
+/// refers to immutable objects, and changes to the root are invalidated explicitly.
+pub const INF_TTL: Duration = Duration::new(1_000_000_000, 0);
+
+/// Inode numbers reported by readdir for entries which have not been looked up are taken from
+/// their ids, with this bit set to keep them apart from allocated ones. The kernel does not use
+/// these for anything but `d_ino`, so there is no need to allocate a real inode.
+pub const UNKNOWN_INO_BIT: u64 = 1 << 39;
+
+/// Block size reported by statfs
+const STATFS_BLOCK_SIZE: u32 = 4096;
+
+/// Longest file name reported by statfs
+const STATFS_NAME_MAX: u32 = 255;
+
+/// Extended attribute holding the id of the fs object behind an inode
+pub const XATTR_ID: &str = "user.seafile.id";
+
+/// Extended attribute holding the ids of the blocks of a file, one per line
+pub const XATTR_BLOCKS: &str = "user.seafile.blocks";
 
-/// Instance of a mounted seafuse filesystem
+/// Extended attribute holding the id of the commit an inode was first found in, or for the root,
+/// the commit being served
+pub const XATTR_COMMIT: &str = "user.seafile.commit";
+
+/// Extended attribute holding the id of the library
+pub const XATTR_REPO_ID: &str = "user.seafile.repo_id";
+
+/// Extended attribute holding the user who last modified a file
+pub const XATTR_MODIFIER: &str = "user.seafile.modifier";
+
+/// Instance of a mounted seafuse filesystem. All state is behind locks, so a single instance can
+/// serve requests from several threads at once.
 #[derive(Debug)]
 pub struct SeafFuse {
     /// Description of the mounted library
     lib: Library,
 
+    /// The commit being served, which may move on from the one the library was opened at
+    commit: RwLock<CommitJson>,
+
+    /// Commits of the library seen so far, to find new ones without reading every commit again
+    commits: Mutex<CommitScan>,
+
     /// Mapping between inode numbers and FS hashes used by seafile
-    ino_table: BiMap<u64, Sha1>,
+    ino_table: RwLock<InodeTable>,
 
     /// Table of currently open files, indexed by file handle
-    open_file_table: HashMap<u64, OpenFile>,
+    open_file_table: RwLock<HashMap<u64, Arc<OpenFile>>>,
+
+    /// Snapshots of currently open directories, indexed by file handle
+    open_dir_table: RwLock<HashMap<u64, Arc<Vec<Dentry>>>>,
+
+    /// The next file handle to be used, for files and directories alike
+    file_handle_counter: AtomicU64,
+
+    /// What statfs reports on
+    statfs_source: StatfsSource,
+
+    /// Usage reported by statfs, along with the commit it was computed for
+    usage: Mutex<Option<(Sha1, UsageStats)>>,
+
+    /// Whether to serve the control directory
+    control_dir: bool,
+
+    /// Used to tell the kernel about entries which changed when switching commits
+    notifier: Mutex<Option<Notifier>>,
+}
+
+/// What statfs reports the usage of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StatfsSource {
+    /// Logical size and number of files of the commit being served
+    #[default]
+    Commit,
+
+    /// Space taken by the block store of the library
+    Blocks,
+}
+
+/// Inodes the kernel currently knows about. An inode lives from the first lookup of it until the
+/// kernel forgets every lookup and any files opened through it have been closed, except for the
+/// root which lives forever.
+#[derive(Debug)]
+struct InodeTable {
+    /// Mapping between inode numbers and FS hashes used by seafile, for every inode but the root
+    map: BiMap<u64, Sha1>,
+
+    /// Id of the root directory of the commit being served. Kept out of `map`, since the same
+    /// object may be found elsewhere under an inode of its own.
+    root: Sha1,
+
+    /// Number of lookups of each inode not yet forgotten by the kernel
+    lookups: HashMap<u64, u64>,
+
+    /// Number of open file handles of each inode
+    opens: HashMap<u64, u64>,
+
+    /// Last modifier of each inode, as recorded in the directory entry it was looked up through.
+    /// Identical files share an inode, so this is the modifier of one of them.
+    modifiers: HashMap<u64, String>,
+
+    /// Commit being served when each inode was allocated. Identical objects share an inode, so
+    /// later commits may hold it as well.
+    commits: HashMap<u64, Sha1>,
 
     /// The next inode number to be allocated
-    ino_counter: u64,
+    counter: u64,
+}
 
-    /// The next file handle to be used
-    file_handle_counter: u64,
+impl InodeTable {
+    fn get_or_insert(&mut self, id: Sha1, commit_id: Sha1) -> u64 {
+        match self.map.get_by_right(&id) {
+            Some(ino) => *ino,
+            None => {
+                let ino = self.counter;
+                self.counter += 1;
+                self.map.insert(ino, id);
+                self.commits.insert(ino, commit_id);
+                ino
+            }
+        }
+    }
+
+    /// Drop the inode if nothing refers to it any more
+    fn evict_if_unused(&mut self, ino: u64) {
+        if self.lookups.contains_key(&ino) || self.opens.contains_key(&ino) {
+            return;
+        }
+
+        self.map.remove_by_left(&ino);
+        self.modifiers.remove(&ino);
+        self.commits.remove(&ino);
+    }
+}
+
+/// Decrement a reference count, removing it when it reaches zero
+fn decrement(counts: &mut HashMap<u64, u64>, ino: u64, n: u64) {
+    if let Some(c) = counts.get_mut(&ino) {
+        *c = c.saturating_sub(n);
+        if *c == 0 {
+            counts.remove(&ino);
+        }
+    }
 }
 
 /// Directory entry
 #[derive(Debug, Clone)]
 pub struct Dentry {
+    pub id: Sha1,
     pub ino: u64,
     pub kind: FileType,
     pub name: OsString,
+    pub attr: FileAttr,
+    pub modifier: Option<String>,
 }
 
 #[derive(Debug)]
 struct OpenFile {
-    reader: FileReader,
+    ino: u64,
+    data: OpenData,
+
+    /// Whether the file was opened for writing
+    writable: bool,
+}
+
+#[derive(Debug)]
+enum OpenData {
+    /// A file in the library
+    Reader(FileReader),
+
+    /// A control file, whose contents were generated when it was opened
+    Generated(Vec<u8>),
 }
 
 /// Intermediate trait to make the fuse implementation testable
 pub trait PreFilesystem {
-    fn do_lookup(&mut self, parent_ino: u64, name: &OsStr) -> Result<FileAttr, c_int>;
+    fn do_lookup(&self, parent_ino: u64, name: &OsStr) -> Result<FileAttr, c_int>;
     fn do_getattr(&self, ino: u64) -> Result<FileAttr, c_int>;
-    fn do_readdir(&mut self, ino: u64) -> Result<Vec<Dentry>, c_int>;
-    fn do_open(&mut self, ino: u64) -> Result<u64, c_int>;
-    fn do_release(&mut self, fh: u64) -> Result<(), c_int>;
-    fn do_read(&mut self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int>;
+    fn do_readdir(&self, ino: u64) -> Result<Vec<Dentry>, c_int>;
+    fn do_opendir(&self, ino: u64) -> Result<u64, c_int>;
+    fn do_dir_entries(&self, fh: u64) -> Result<Arc<Vec<Dentry>>, c_int>;
+    fn do_releasedir(&self, fh: u64) -> Result<(), c_int>;
+    fn do_open(&self, ino: u64, flags: i32) -> Result<u64, c_int>;
+    fn do_release(&self, fh: u64) -> Result<(), c_int>;
+    fn do_read(&self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int>;
+    fn do_write(&self, fh: u64, data: &[u8]) -> Result<u32, c_int>;
+    fn do_forget(&self, ino: u64, nlookup: u64);
+    fn do_statfs(&self) -> Result<UsageStats, c_int>;
+
+    /// Get the value of the extended attribute `name`
+    fn do_getxattr(&self, ino: u64, name: &OsStr) -> Result<Vec<u8>, c_int>;
+
+    /// Get the names of all extended attributes, each terminated by a NUL byte
+    fn do_listxattr(&self, ino: u64) -> Result<Vec<u8>, c_int>;
+
+    /// Take a lookup reference to an entry of the open directory `fh`, as when passing it to the
+    /// kernel through readdirplus, and get its attributes
+    fn do_ref_dentry(&self, fh: u64, d: &Dentry) -> FileAttr;
+
+    /// Whether reads of `ino` should bypass the page cache, for files whose contents change
+    fn do_direct_io(&self, _ino: u64) -> bool {
+        false
+    }
+
+    /// Called once the filesystem has been unmounted
+    fn do_destroy(&self) {}
+}
+
+/// Serves a `SeafFuse` instance to the kernel, handing each request to a pool of worker threads
+/// so that one slow read does not hold up the rest of the mount.
+pub struct FuseServer<F: PreFilesystem> {
+    fs: Arc<F>,
+    pool: Option<ThreadPool>,
+
+    /// Time the kernel may cache name lookups for
+    entry_ttl: Duration,
+
+    /// Time the kernel may cache attributes for
+    attr_ttl: Duration,
 }
 
 impl SeafFuse {
@@ -66,107 +252,341 @@ impl SeafFuse {
         let root_id = lib.head_commit.root_id;
 
         SeafFuse {
+            commit: RwLock::new(lib.head_commit.clone()),
+            commits: Mutex::new(CommitScan::default()),
             lib,
-            ino_table: BiMap::from_iter([(FUSE_ROOT_ID, root_id)]),
-            open_file_table: HashMap::new(),
-            ino_counter: FUSE_ROOT_ID + 1,
-            file_handle_counter: 1,
+            ino_table: RwLock::new(InodeTable {
+                map: BiMap::new(),
+                root: root_id,
+                lookups: HashMap::new(),
+                opens: HashMap::new(),
+                modifiers: HashMap::new(),
+                commits: HashMap::new(),
+                counter: FIRST_DYNAMIC_INO,
+            }),
+            open_file_table: RwLock::new(HashMap::new()),
+            open_dir_table: RwLock::new(HashMap::new()),
+            file_handle_counter: AtomicU64::new(1),
+            statfs_source: StatfsSource::default(),
+            usage: Mutex::new(None),
+            control_dir: false,
+            notifier: Mutex::new(None),
         }
     }
 
-    fn lookup_attr_by_id(&mut self, id: Sha1) -> Result<FileAttr, c_int> {
-        let ino = self.add_ino(id);
-        self.lookup_attr_by_ino(ino)
+    /// Serve the `.seafuse` control directory in the root of the mount
+    pub fn with_control_dir(mut self, enabled: bool) -> SeafFuse {
+        self.control_dir = enabled;
+        self
+    }
+
+    pub fn has_control_dir(&self) -> bool {
+        self.control_dir
+    }
+
+    /// Let the filesystem tell the kernel to drop cached entries when switching commits
+    pub fn set_notifier(&self, notifier: Notifier) {
+        *self.notifier.lock().unwrap() = Some(notifier);
+    }
+
+    /// Choose what statfs reports on
+    pub fn with_statfs(mut self, source: StatfsSource) -> SeafFuse {
+        self.statfs_source = source;
+        self
+    }
+
+    /// The commit currently being served
+    pub fn commit(&self) -> CommitJson {
+        self.commit.read().unwrap().clone()
+    }
+
+    /// Serve `commit` from now on. Inodes other than the root refer to immutable objects, so they
+    /// and any open files stay valid. Returns the names of the entries in the root directory
+    /// which differ between the two commits, and which the kernel must be told to forget.
+    pub fn switch_commit(&self, commit: CommitJson) -> Result<Vec<OsString>, SeafError> {
+        let mut current = self.commit.write().unwrap();
+        let old_root = self.lib.load_fs_shared(current.root_id)?.as_ref().clone();
+        let new_root = self.lib.load_fs_shared(commit.root_id)?.as_ref().clone();
+        let changed = changed_names(&old_root.try_dir()?, &new_root.try_dir()?);
+
+        info!(
+            "Switching from commit {} to {}",
+            current.commit_id, commit.commit_id
+        );
+
+        self.ino_table.write().unwrap().root = commit.root_id;
+        *current = commit;
+
+        Ok(changed)
+    }
+
+    /// Switch to `commit`, telling the kernel to forget whatever changed
+    pub fn checkout(&self, commit: CommitJson) -> Result<(), SeafError> {
+        let changed = self.switch_commit(commit)?;
+        self.invalidate(&changed);
+        Ok(())
+    }
+
+    /// Tell the kernel to drop what it has cached about the named entries of the root directory
+    fn invalidate(&self, changed: &[OsString]) {
+        let Some(notifier) = self.notifier.lock().unwrap().clone() else {
+            return;
+        };
+
+        // The kernel may well not have these cached, so failures are expected here
+        for name in changed {
+            if let Err(e) = notifier.inval_entry(FUSE_ROOT_ID, name) {
+                debug!("Failed to invalidate entry {name:?}: {e}");
+            }
+        }
+
+        if let Err(e) = notifier.inval_inode(FUSE_ROOT_ID, 0, 0) {
+            debug!("Failed to invalidate root inode: {e}");
+        }
+    }
+
+    /// Switch to the newest commit of the library, if it is not already being served. Returns
+    /// the changed names as `switch_commit` does, or None if there was nothing to do.
+    pub fn refresh(&self) -> Result<Option<Vec<OsString>>, SeafError> {
+        let latest = self.commits.lock().unwrap().latest(&self.lib)?;
+
+        if latest.commit_id == self.commit.read().unwrap().commit_id {
+            return Ok(None);
+        }
+
+        self.switch_commit(latest).map(Some)
+    }
+
+    /// Number of inodes currently known to the kernel
+    pub fn inode_count(&self) -> usize {
+        self.ino_table.read().unwrap().map.len() + 1
+    }
+
+    fn lookup_attr_by_dirent(&self, de: &DirentJson) -> Result<FileAttr, c_int> {
+        let fs = self.lookup_fs(de.id)?;
+        let ino = self.ref_ino(de.id, de.modifier.as_deref());
+        Ok(make_attr(ino, &fs))
     }
 
     fn lookup_attr_by_ino(&self, ino: u64) -> Result<FileAttr, c_int> {
         let id = self.lookup_id_by_ino(ino)?;
         let fs = self.lookup_fs(id)?;
 
-        match fs {
-            FsJson::Dir(_) => Ok(FileAttr {
-                ino,
-                size: 0,
-                blocks: 0,
-                atime: UNIX_EPOCH,
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: FileType::Directory,
-                perm: 0o755,
-                nlink: 1,
-                uid: 0,
-                gid: 0,
-                rdev: 0,
-                blksize: 0,
-                flags: 0,
-            }),
-            FsJson::File(f) => Ok(FileAttr {
-                ino,
-                size: f.size,
-                blocks: 0,
-                atime: UNIX_EPOCH,
-                mtime: UNIX_EPOCH,
-                ctime: UNIX_EPOCH,
-                crtime: UNIX_EPOCH,
-                kind: FileType::RegularFile,
-                perm: 0o644,
-                nlink: 1,
-                uid: 0,
-                gid: 0,
-                rdev: 0,
-                blksize: 0,
-                flags: 0,
-            }),
-        }
+        Ok(make_attr(ino, &fs))
     }
 
     fn lookup_id_by_ino(&self, ino: u64) -> Result<Sha1, c_int> {
-        match self.ino_table.get_by_left(&ino) {
+        let table = self.ino_table.read().unwrap();
+        if ino == FUSE_ROOT_ID {
+            return Ok(table.root);
+        }
+
+        match table.map.get_by_left(&ino) {
             None => {
                 error!("Inode {ino} does not exist");
-                Err(EIO)
+                Err(ESTALE)
             }
             Some(id) => Ok(*id),
         }
     }
 
-    fn add_ino(&mut self, id: Sha1) -> u64 {
-        match self.ino_table.get_by_right(&id) {
-            Some(ino) => *ino,
-            None => {
-                let ino = self.ino_counter;
-                self.ino_counter += 1;
-                self.ino_table.insert(ino, id);
-                ino
-            }
+    /// Get the inode for `id`, allocating one if needed, and count one more lookup of it
+    fn ref_ino(&self, id: Sha1, modifier: Option<&str>) -> u64 {
+        let commit_id = self.commit.read().unwrap().commit_id;
+        let mut table = self.ino_table.write().unwrap();
+        let ino = table.get_or_insert(id, commit_id);
+        *table.lookups.entry(ino).or_default() += 1;
+        if let Some(m) = modifier {
+            table.modifiers.insert(ino, m.to_string());
         }
+        ino
     }
 
-    fn lookup_file(&self, id: Sha1) -> Result<FileJson, c_int> {
-        self.lookup_fs(id)?.try_file().map_err(|e| {
-            error!("Fs {id} is not a file: {e:?}");
+    /// All extended attributes of `ino` which have a value
+    fn xattrs(&self, ino: u64) -> Result<Vec<(&'static str, Vec<u8>)>, c_int> {
+        if self.is_control(ino) {
+            return Ok(vec![]);
+        }
+
+        let id = self.lookup_id_by_ino(ino)?;
+        let commit = self.commit();
+        let mut attrs = vec![(XATTR_ID, id.to_string().into_bytes())];
+
+        if let FsJson::File(f) = self.lookup_fs(id)?.as_ref() {
+            let blocks: String = f.block_ids.iter().map(|b| format!("{b}\n")).collect();
+            attrs.push((XATTR_BLOCKS, blocks.into_bytes()));
+        }
+
+        let table = self.ino_table.read().unwrap();
+        let commit_id = match ino {
+            FUSE_ROOT_ID => commit.commit_id,
+            _ => table.commits.get(&ino).copied().unwrap_or(commit.commit_id),
+        };
+        attrs.push((XATTR_COMMIT, commit_id.to_string().into_bytes()));
+        attrs.push((XATTR_REPO_ID, commit.repo_id.into_bytes()));
+
+        if let Some(m) = table.modifiers.get(&ino) {
+            attrs.push((XATTR_MODIFIER, m.clone().into_bytes()));
+        }
+
+        Ok(attrs)
+    }
+
+    /// Get the inode for `id` if the kernel already knows it
+    fn peek_ino(&self, id: Sha1) -> Option<u64> {
+        self.ino_table
+            .read()
+            .unwrap()
+            .map
+            .get_by_right(&id)
+            .copied()
+    }
+
+    /// Whether `ino` is the control directory or one of its files
+    fn is_control(&self, ino: u64) -> bool {
+        self.control_dir && (ino == CONTROL_DIR_INO || ControlFile::by_ino(ino).is_some())
+    }
+
+    fn control_dirent(&self, f: ControlFile) -> Dentry {
+        Dentry {
+            id: Sha1::default(),
+            ino: f.ino(),
+            kind: FileType::RegularFile,
+            name: f.name().into(),
+            attr: f.attr(),
+            modifier: None,
+        }
+    }
+
+    /// Generate the contents of a control file
+    fn control_file_data(&self, f: ControlFile) -> Result<Vec<u8>, SeafError> {
+        let commit = self.commit();
+        let mut data = match f {
+            ControlFile::Commit => {
+                serde_json::to_vec_pretty(&commit).expect("Failed to serialize commit")
+            }
+            ControlFile::Library => {
+                let location = &self.lib.location;
+                let latest = self.commits.lock().unwrap().latest(&self.lib)?;
+                let info = serde_json::json!({
+                    "repo_id": location.uuid,
+                    "storage": location.repo_path,
+                    "repo_name": commit.repo_name,
+                    "repo_desc": commit.repo_desc,
+                    "encrypted": commit.encrypted,
+                    "commit_id": commit.commit_id,
+                    "latest_commit_id": latest.commit_id,
+                });
+                serde_json::to_vec_pretty(&info).expect("Failed to serialize library")
+            }
+            ControlFile::Stats => {
+                let mut stats = String::new();
+                for (name, s) in [
+                    ("fs_cache", self.lib.fs_cache_stats()),
+                    ("block_file_cache", self.lib.block_file_cache_stats()),
+                    ("block_cache", self.lib.block_cache_stats()),
+                ] {
+                    stats += &format!(
+                        "{name}.hits {}\n{name}.misses {}\n{name}.entries {}\n{name}.capacity {}\n",
+                        s.hits, s.misses, s.entries, s.capacity
+                    );
+                }
+                stats += &format!("inodes {}\n", self.inode_count());
+                stats += &format!(
+                    "open_files {}\n",
+                    self.open_file_table.read().unwrap().len()
+                );
+                stats += &format!("open_dirs {}", self.open_dir_table.read().unwrap().len());
+                stats.into_bytes()
+            }
+            ControlFile::Ctl => return Ok(ctl_usage()),
+        };
+
+        data.push(b'\n');
+        Ok(data)
+    }
+
+    /// Carry out a command written to `ctl`
+    fn run_command(&self, line: &str) -> Result<(), c_int> {
+        let cmd = ControlCommand::parse(line).map_err(|e| {
+            error!("{e}");
             EINVAL
-        })
+        })?;
+
+        info!("Running control command {cmd:?}");
+
+        let r = match cmd {
+            ControlCommand::Refresh => self
+                .refresh()
+                .map(|changed| self.invalidate(&changed.unwrap_or_default())),
+            ControlCommand::CheckoutId(id) => {
+                self.lib.commit_by_id(id).and_then(|c| self.checkout(c))
+            }
+            ControlCommand::CheckoutTime(t) => match self.lib.commit_at(t) {
+                Ok(Some(c)) => self.checkout(c),
+                Ok(None) => {
+                    error!("No commit made before {t}");
+                    return Err(ENOENT);
+                }
+                Err(e) => Err(e),
+            },
+        };
+
+        r.map_err(|e| self.fail(&format!("run {line:?}"), &e))
+    }
+
+    /// Log a failed operation along with the library it failed in, and get the errno to reply
+    /// with. `what` names the operation and the object it was done on.
+    fn fail(&self, what: &str, e: &SeafError) -> c_int {
+        let errno = e.errno();
+        error!(
+            "Failed to {what} in library {}: {} (errno {errno})",
+            self.lib.location.uuid,
+            e.report()
+        );
+        errno
+    }
+
+    fn lookup_file(&self, id: Sha1) -> Result<FileJson, c_int> {
+        match self.lookup_fs(id)?.as_ref().clone().try_file() {
+            Ok(f) => Ok(f),
+            Err(SeafError::WrongFsType) => {
+                debug!("Fs {id} is not a file");
+                Err(EISDIR)
+            }
+            Err(e) => Err(self.fail(&format!("read file {id}"), &e)),
+        }
     }
 
     fn lookup_dir(&self, id: Sha1) -> Result<DirJson, c_int> {
-        self.lookup_fs(id)?.try_dir().map_err(|e| {
-            error!("Fs {id} is not a dir: {e:?}");
-            ENOTDIR
-        })
+        self.lookup_fs(id)?
+            .as_ref()
+            .clone()
+            .try_dir()
+            .map_err(|e| self.fail(&format!("read dir {id}"), &e))
     }
 
-    fn lookup_fs(&self, id: Sha1) -> Result<FsJson, c_int> {
-        self.lib.load_fs(id).map_err(|e| {
-            error!("Failed to load Fs with id {id}: {e:?}");
-            EINVAL
-        })
+    fn lookup_fs(&self, id: Sha1) -> Result<Arc<FsJson>, c_int> {
+        self.lib
+            .load_fs_shared(id)
+            .map_err(|e| self.fail(&format!("load fs {id}"), &e))
     }
 
-    fn get_open_file(&mut self, fh: u64) -> Result<&mut OpenFile, c_int> {
-        match self.open_file_table.get_mut(&fh) {
-            Some(of) => Ok(of),
+    /// Allocate a file handle and store `value` under it in `table`
+    fn insert_handle<T>(&self, table: &RwLock<HashMap<u64, T>>, value: T) -> u64 {
+        let mut table = table.write().unwrap();
+        let mut fh = self.file_handle_counter.fetch_add(1, Ordering::Relaxed);
+        while table.contains_key(&fh) {
+            fh = self.file_handle_counter.fetch_add(1, Ordering::Relaxed);
+        }
+
+        table.insert(fh, value);
+        fh
+    }
+
+    fn get_open_file(&self, fh: u64) -> Result<Arc<OpenFile>, c_int> {
+        match self.open_file_table.read().unwrap().get(&fh) {
+            Some(of) => Ok(of.clone()),
             None => {
                 error!("Bad file handle {fh}");
                 Err(EBADF)
@@ -176,75 +596,170 @@ impl SeafFuse {
 }
 
 impl PreFilesystem for SeafFuse {
-    fn do_lookup(&mut self, parent_ino: u64, name: &OsStr) -> Result<FileAttr, c_int> {
-        let parent_id = self.lookup_id_by_ino(parent_ino)?;
-        let parent_dir = self.lookup_dir(parent_id)?;
-
-        for de in &parent_dir.dirents {
-            if Some(de.name.as_ref()) != name.to_str() {
-                continue;
-            }
+    fn do_lookup(&self, parent_ino: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        if self.control_dir && parent_ino == FUSE_ROOT_ID && name == CONTROL_DIR_NAME {
+            return Ok(control_dir_attr(CONTROL_DIR_INO));
+        }
 
-            return self.lookup_attr_by_id(de.id);
+        if self.is_control(parent_ino) {
+            return match name.to_str().and_then(ControlFile::by_name) {
+                Some(f) if parent_ino == CONTROL_DIR_INO => Ok(f.attr()),
+                Some(_) | None => Err(ENOENT),
+            };
         }
 
-        Err(ENOENT)
+        let parent_id = self.lookup_id_by_ino(parent_ino)?;
+        let Some(name) = name.to_str() else {
+            return Err(ENOENT);
+        };
+
+        match self.lib.lookup_dirent(parent_id, name) {
+            Ok(Some(de)) => self.lookup_attr_by_dirent(&de),
+            Ok(None) => Err(ENOENT),
+            Err(e) => Err(self.fail(&format!("look up {name:?} in dir {parent_id}"), &e)),
+        }
     }
 
     fn do_getattr(&self, ino: u64) -> Result<FileAttr, c_int> {
+        if self.is_control(ino) {
+            return Ok(match ControlFile::by_ino(ino) {
+                Some(f) => f.attr(),
+                None => control_dir_attr(ino),
+            });
+        }
+
         self.lookup_attr_by_ino(ino)
     }
 
-    fn do_readdir(&mut self, ino: u64) -> Result<Vec<Dentry>, c_int> {
+    fn do_readdir(&self, ino: u64) -> Result<Vec<Dentry>, c_int> {
+        // The control directory itself is left out of the root listing, so that it does not get
+        // in the way of tools walking the tree
+        if self.is_control(ino) {
+            return match ino {
+                CONTROL_DIR_INO => Ok(CONTROL_FILES.map(|f| self.control_dirent(f)).to_vec()),
+                _ => Err(ENOTDIR),
+            };
+        }
+
         let id = self.lookup_id_by_ino(ino)?;
         let dir = self.lookup_dir(id)?;
         let mut results = vec![];
 
         for de in dir.dirents {
-            let de_ino = self.add_ino(de.id);
-            let de_fs = self.lib.load_fs(de.id).map_err(|_e| EIO)?;
+            let de_ino = self.peek_ino(de.id).unwrap_or_else(|| unknown_ino(de.id));
+            let de_fs = self.lookup_fs(de.id)?;
+            let attr = make_attr(de_ino, &de_fs);
 
             results.push(Dentry {
+                id: de.id,
                 ino: de_ino,
-                kind: match de_fs {
-                    FsJson::Dir(_) => FileType::Directory,
-                    FsJson::File(_) => FileType::RegularFile,
-                },
+                kind: attr.kind,
                 name: OsString::from(de.name),
+                attr,
+                modifier: de.modifier,
             });
         }
 
         Ok(results)
     }
 
-    fn do_open(&mut self, ino: u64) -> Result<u64, c_int> {
-        while self.open_file_table.contains_key(&self.file_handle_counter) {
-            self.file_handle_counter += 1;
+    fn do_opendir(&self, ino: u64) -> Result<u64, c_int> {
+        // Take a snapshot of the listing, so that readdir can page through it without rebuilding
+        // it for every call
+        let entries = Arc::new(self.do_readdir(ino)?);
+        let count = entries.len();
+        let fh = self.insert_handle(&self.open_dir_table, entries);
+
+        debug!("Open dir fh={fh} ino={ino} entries={count}");
+
+        Ok(fh)
+    }
+
+    fn do_dir_entries(&self, fh: u64) -> Result<Arc<Vec<Dentry>>, c_int> {
+        match self.open_dir_table.read().unwrap().get(&fh) {
+            Some(entries) => Ok(entries.clone()),
+            None => {
+                error!("Bad dir handle {fh}");
+                Err(EBADF)
+            }
         }
+    }
 
-        let id = self.lookup_id_by_ino(ino)?;
-        let file = self.lookup_file(id)?;
-        let reader = self.lib.file_reader(&file).map_err(|e| {
-            error!("Failed to open file {id} for reading: {e:?}");
-            EIO
-        })?;
-        let of = OpenFile { reader };
+    fn do_releasedir(&self, fh: u64) -> Result<(), c_int> {
+        debug!("Close dir fh={fh}");
 
-        let fh = self.file_handle_counter;
-        self.file_handle_counter += 1;
+        match self.open_dir_table.write().unwrap().remove(&fh) {
+            Some(_) => Ok(()),
+            None => {
+                error!("Invalid dir handle {fh}");
+                Err(EBADF)
+            }
+        }
+    }
 
-        self.open_file_table.insert(fh, of);
+    fn do_open(&self, ino: u64, flags: i32) -> Result<u64, c_int> {
+        let writable = flags & O_ACCMODE != O_RDONLY;
+
+        if self.is_control(ino) {
+            let f = ControlFile::by_ino(ino).ok_or(EISDIR)?;
+            if writable && !f.writable() {
+                return Err(EACCES);
+            }
+
+            let data = self
+                .control_file_data(f)
+                .map_err(|e| self.fail(&format!("generate {}", f.name()), &e))?;
+            let of = Arc::new(OpenFile {
+                ino,
+                data: OpenData::Generated(data),
+                writable,
+            });
+
+            return Ok(self.insert_handle(&self.open_file_table, of));
+        }
+
+        if writable {
+            return Err(EROFS);
+        }
+
+        let id = self.lookup_id_by_ino(ino)?;
+        self.lookup_file(id)?;
+        let reader = self
+            .lib
+            .file_reader_by_id(id)
+            .map_err(|e| self.fail(&format!("open file {id}"), &e))?;
+        let of = Arc::new(OpenFile {
+            ino,
+            data: OpenData::Reader(reader),
+            writable,
+        });
+        let fh = self.insert_handle(&self.open_file_table, of);
+
+        // Keep the inode alive for as long as the file is open
+        *self
+            .ino_table
+            .write()
+            .unwrap()
+            .opens
+            .entry(ino)
+            .or_default() += 1;
 
         debug!("Open file fh={fh} id={id}");
 
         Ok(fh)
     }
 
-    fn do_release(&mut self, fh: u64) -> Result<(), c_int> {
+    fn do_release(&self, fh: u64) -> Result<(), c_int> {
         debug!("Close file fh={fh}");
 
-        match self.open_file_table.remove(&fh) {
-            Some(_) => Ok(()),
+        let of = self.open_file_table.write().unwrap().remove(&fh);
+        match of {
+            Some(of) => {
+                let mut table = self.ino_table.write().unwrap();
+                decrement(&mut table.opens, of.ino, 1);
+                table.evict_if_unused(of.ino);
+                Ok(())
+            }
             None => {
                 error!("Invalid file handle {fh}");
                 Err(EBADF)
@@ -252,74 +767,314 @@ impl PreFilesystem for SeafFuse {
         }
     }
 
-    fn do_read(&mut self, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+    fn do_read(&self, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
         let of = self.get_open_file(fh)?;
         let mut buf = vec![0; size as usize];
 
-        // XXX Is it correct to cast to u64 here? Does negative offset mean something?
-        of.reader
-            .seek(SeekFrom::Start(offset as u64))
-            .map_err(|_e| EINVAL)?;
-        let r = of.reader.read(&mut buf).map_err(|_e| EIO)?;
+        if offset < 0 {
+            error!("Negative read offset {offset} for fh={fh}");
+            return Err(EINVAL);
+        }
+
+        let r = match &of.data {
+            OpenData::Reader(reader) => reader
+                .try_read_at(offset as u64, &mut buf)
+                .map_err(|e| self.fail(&format!("read inode {} at {offset}", of.ino), &e))?,
+            OpenData::Generated(data) => {
+                let src = data.get(offset as usize..).unwrap_or_default();
+                let n = min(src.len(), buf.len());
+                buf[..n].copy_from_slice(&src[..n]);
+                n
+            }
+        };
 
         buf.truncate(r);
 
         debug!("Read {} bytes: {}...", r, sample_bytes(&buf));
         Ok(buf)
     }
+
+    fn do_write(&self, fh: u64, data: &[u8]) -> Result<u32, c_int> {
+        let of = self.get_open_file(fh)?;
+        if !of.writable {
+            return Err(EBADF);
+        }
+
+        let Ok(text) = std::str::from_utf8(data) else {
+            error!("Control command is not valid UTF-8");
+            return Err(EINVAL);
+        };
+
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            self.run_command(line)?;
+        }
+
+        Ok(data.len() as u32)
+    }
+
+    fn do_direct_io(&self, ino: u64) -> bool {
+        self.is_control(ino)
+    }
+
+    fn do_forget(&self, ino: u64, nlookup: u64) {
+        let mut table = self.ino_table.write().unwrap();
+
+        if !table.lookups.contains_key(&ino) {
+            debug!("Forget of unknown inode {ino}");
+            return;
+        }
+
+        decrement(&mut table.lookups, ino, nlookup);
+        table.evict_if_unused(ino);
+    }
+
+    fn do_statfs(&self) -> Result<UsageStats, c_int> {
+        // Computing usage means walking the whole tree or block store, so do it once per commit
+        let commit = self.commit();
+        if let Some((id, u)) = *self.usage.lock().unwrap() {
+            if id == commit.commit_id {
+                return Ok(u);
+            }
+        }
+
+        // Not under the lock, so that a long walk holds up no one else. Two callers may end up
+        // doing the same work, which does no harm.
+        let u = match self.statfs_source {
+            StatfsSource::Commit => self.lib.tree_usage(commit.root_id),
+            StatfsSource::Blocks => self.lib.block_usage(),
+        }
+        .map_err(|e| self.fail("compute usage", &e))?;
+
+        *self.usage.lock().unwrap() = Some((commit.commit_id, u));
+        Ok(u)
+    }
+
+    fn do_ref_dentry(&self, _fh: u64, d: &Dentry) -> FileAttr {
+        if self.is_control(d.ino) {
+            return d.attr;
+        }
+
+        FileAttr {
+            ino: self.ref_ino(d.id, d.modifier.as_deref()),
+            ..d.attr
+        }
+    }
+
+    fn do_getxattr(&self, ino: u64, name: &OsStr) -> Result<Vec<u8>, c_int> {
+        self.xattrs(ino)?
+            .into_iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value)
+            .ok_or(ENODATA)
+    }
+
+    fn do_listxattr(&self, ino: u64) -> Result<Vec<u8>, c_int> {
+        let mut names = vec![];
+        for (name, _) in self.xattrs(ino)? {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+
+        Ok(names)
+    }
+
+    fn do_destroy(&self) {
+        debug!("Fs cache: {:?}", self.lib.fs_cache_stats());
+        debug!("Block file cache: {:?}", self.lib.block_file_cache_stats());
+        debug!("Block cache: {:?}", self.lib.block_cache_stats());
+        debug!("Inodes: {}", self.inode_count());
+    }
 }
 
-impl Filesystem for SeafFuse {
+impl<F: PreFilesystem + Send + Sync + 'static> FuseServer<F> {
+    pub fn fs(&self) -> Arc<F> {
+        self.fs.clone()
+    }
+
+    /// Serve `fs` using `threads` worker threads. With zero threads, every request is handled
+    /// directly on the session thread.
+    pub fn new(fs: F, threads: usize) -> FuseServer<F> {
+        let pool = if threads > 0 {
+            Some(
+                threadpool::Builder::new()
+                    .num_threads(threads)
+                    .thread_name("seafuse-worker".into())
+                    .build(),
+            )
+        } else {
+            None
+        };
+
+        FuseServer {
+            fs: Arc::new(fs),
+            pool,
+            entry_ttl: INF_TTL,
+            attr_ttl: INF_TTL,
+        }
+    }
+
+    /// Let the kernel cache name lookups for `entry_ttl` and attributes for `attr_ttl`
+    pub fn with_ttl(mut self, entry_ttl: Duration, attr_ttl: Duration) -> FuseServer<F> {
+        self.entry_ttl = entry_ttl;
+        self.attr_ttl = attr_ttl;
+        self
+    }
+
+    fn spawn<J>(&self, job: J)
+    where
+        J: FnOnce(&F) + Send + 'static,
+    {
+        match &self.pool {
+            Some(pool) => {
+                let fs = self.fs.clone();
+                pool.execute(move || job(&fs));
+            }
+            None => job(&self.fs),
+        }
+    }
+}
+
+impl<F: PreFilesystem + Send + Sync + 'static> Filesystem for FuseServer<F> {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        if let Err(e) =
+            config.add_capabilities(consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO)
+        {
+            debug!("Kernel does not support readdirplus: {e:#x}");
+        }
+
+        // Lets `ctl` be opened with O_TRUNC without a separate truncate request
+        if let Err(e) = config.add_capabilities(consts::FUSE_ATOMIC_O_TRUNC) {
+            debug!("Kernel does not support atomic O_TRUNC: {e:#x}");
+        }
+
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.join();
+        }
+
+        self.fs.do_destroy();
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.fs.do_forget(ino, nlookup);
+    }
+
+    fn batch_forget(&mut self, _req: &Request<'_>, nodes: &[fuse_forget_one]) {
+        for n in nodes {
+            self.fs.do_forget(n.nodeid, n.nlookup);
+        }
+    }
+
     fn access(&mut self, _req: &Request, _ino: u64, _mask: i32, reply: ReplyEmpty) {
         reply.ok();
     }
 
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        match self.do_lookup(parent, name) {
-            Ok(attr) => reply.entry(&INF_TTL, &attr, 0),
-            Err(r) => reply.error(r),
-        };
+        let name = name.to_owned();
+        let ttl = self.entry_ttl;
+        self.spawn(move |fs| {
+            match fs.do_lookup(parent, &name) {
+                Ok(attr) => reply.entry(&ttl, &attr, 0),
+                Err(r) => reply.error(r),
+            };
+        });
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        match self.do_getattr(ino) {
-            Ok(attr) => reply.attr(&INF_TTL, &attr),
+        let ttl = self.attr_ttl;
+        self.spawn(move |fs| {
+            match fs.do_getattr(ino) {
+                Ok(attr) => reply.attr(&ttl, &attr),
+                Err(r) => reply.error(r),
+            };
+        });
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        self.spawn(move |fs| match fs.do_opendir(ino) {
+            Ok(fh) => reply.opened(fh, 0),
             Err(r) => reply.error(r),
-        };
+        });
     }
 
     fn readdir(
         &mut self,
         _req: &Request,
-        ino: u64,
-        _fh: u64,
+        _ino: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        match self.do_readdir(ino) {
-            Ok(dentries) => {
-                for (i, d) in dentries.into_iter().enumerate() {
-                    let i = (i + 1) as i64;
-                    if i <= offset {
-                        continue;
+        self.spawn(move |fs| {
+            match fs.do_dir_entries(fh) {
+                Ok(dentries) => {
+                    let start = offset.max(0) as usize;
+                    for (i, d) in dentries.iter().enumerate().skip(start) {
+                        if reply.add(d.ino, (i + 1) as i64, d.kind, &d.name) {
+                            break;
+                        }
                     }
 
-                    if reply.add(d.ino, i, d.kind, d.name.clone()) {
-                        break;
+                    reply.ok();
+                }
+                Err(r) => reply.error(r),
+            };
+        });
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let ttl = self.entry_ttl;
+        self.spawn(move |fs| {
+            match fs.do_dir_entries(fh) {
+                Ok(dentries) => {
+                    let start = offset.max(0) as usize;
+                    for (i, d) in dentries.iter().enumerate().skip(start) {
+                        // Every entry passed to the kernel counts as a lookup, so give the
+                        // reference back if the entry did not fit
+                        let attr = fs.do_ref_dentry(fh, d);
+                        if reply.add(attr.ino, (i + 1) as i64, &d.name, &ttl, &attr, 0) {
+                            fs.do_forget(attr.ino, 1);
+                            break;
+                        }
                     }
+
+                    reply.ok();
                 }
+                Err(r) => reply.error(r),
+            };
+        });
+    }
 
-                reply.ok();
-            }
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.spawn(move |fs| match fs.do_releasedir(fh) {
+            Ok(_) => reply.ok(),
             Err(r) => reply.error(r),
-        };
+        });
     }
 
     fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-        match self.do_open(ino) {
-            Ok(fh) => reply.opened(fh, flags as u32), // XXX why is a cast needed?
+        self.spawn(move |fs| match fs.do_open(ino, flags) {
+            Ok(fh) if fs.do_direct_io(ino) => reply.opened(fh, consts::FOPEN_DIRECT_IO),
+            Ok(fh) => reply.opened(fh, 0),
             Err(r) => reply.error(r),
-        }
+        });
     }
 
     fn release(
@@ -332,10 +1087,133 @@ impl Filesystem for SeafFuse {
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        match self.do_release(fh) {
+        self.spawn(move |fs| match fs.do_release(fh) {
             Ok(_) => reply.ok(),
             Err(r) => reply.error(r),
-        }
+        });
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        self.spawn(move |fs| match fs.do_statfs() {
+            Ok(u) => {
+                let bsize = STATFS_BLOCK_SIZE;
+                let blocks = u.bytes.div_ceil(bsize as u64);
+                let files = u.files + u.dirs;
+                reply.statfs(blocks, 0, 0, files, 0, bsize, STATFS_NAME_MAX, bsize);
+            }
+            Err(r) => reply.error(r),
+        });
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let data = data.to_vec();
+        self.spawn(move |fs| match fs.do_write(fh, &data) {
+            Ok(n) => reply.written(n),
+            Err(r) => reply.error(r),
+        });
+    }
+
+    // The mount is only writable when serving the control directory. Nothing else can be
+    // changed.
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        _size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(EROFS);
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(EROFS);
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        reply.error(EROFS);
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let name = name.to_owned();
+        self.spawn(move |fs| match fs.do_getxattr(ino, &name) {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(r) => reply.error(r),
+        });
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.spawn(move |fs| match fs.do_listxattr(ino) {
+            Ok(names) => reply_xattr(reply, size, &names),
+            Err(r) => reply.error(r),
+        });
     }
 
     fn read(
@@ -349,13 +1227,109 @@ impl Filesystem for SeafFuse {
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        match self.do_read(fh, offset, size) {
+        self.spawn(move |fs| match fs.do_read(fh, offset, size) {
             Ok(buf) => reply.data(&buf),
             Err(r) => reply.error(r),
+        });
+    }
+}
+
+/// Reply to an xattr request. A size of zero asks for the size of the data only.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// Keep `fs` on the newest commit of its library, checking every `interval` or whenever a
+/// message arrives on `wakeup`. The kernel is told to drop whatever it has cached about entries
+/// which changed, if `fs` has a notifier. Returns once `wakeup` is disconnected.
+pub fn follow_head(fs: Arc<SeafFuse>, interval: Duration, wakeup: Receiver<()>) {
+    loop {
+        match wakeup.recv_timeout(interval) {
+            Ok(()) => debug!("Refresh requested"),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        match fs.refresh() {
+            Ok(Some(changed)) => fs.invalidate(&changed),
+            Ok(None) => {}
+            Err(e) => error!("Failed to refresh head commit: {}", e.report()),
         }
     }
 }
 
+/// Names of the entries which were added, removed or changed between two versions of a directory
+fn changed_names(old: &DirJson, new: &DirJson) -> Vec<OsString> {
+    let old_ids: HashMap<&str, Sha1> = old.dirents.iter().map(|de| (&*de.name, de.id)).collect();
+    let new_ids: HashMap<&str, Sha1> = new.dirents.iter().map(|de| (&*de.name, de.id)).collect();
+    let mut changed: Vec<OsString> = old_ids
+        .iter()
+        .filter(|(name, id)| new_ids.get(*name) != Some(*id))
+        .chain(
+            new_ids
+                .iter()
+                .filter(|(name, _)| !old_ids.contains_key(*name)),
+        )
+        .map(|(name, _)| OsString::from(name))
+        .collect();
+
+    changed.sort();
+    changed
+}
+
+/// Inode number for readdir to report for the entry with the given id before it is looked up.
+/// Stays the same for as long as the entry is not looked up, and is shared by identical objects
+/// just like allocated inodes are.
+fn unknown_ino(id: Sha1) -> u64 {
+    UNKNOWN_INO_BIT | (id.leading_u64() & (UNKNOWN_INO_BIT - 1))
+}
+
+/// Attributes of the inode `ino`, which refers to the fs object `fs`
+pub(crate) fn make_attr(ino: u64, fs: &FsJson) -> FileAttr {
+    match fs {
+        FsJson::Dir(_) => FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0,
+            flags: 0,
+        },
+        FsJson::File(f) => FileAttr {
+            ino,
+            size: f.size,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0,
+            flags: 0,
+        },
+    }
+}
+
 /// Get the first few bytes of the array, formatted as string
 fn sample_bytes(buf: &[u8]) -> String {
     let slice = &buf[0..min(buf.len(), 32)];
@@ -0,0 +1,215 @@
+// Copyright 2025 Johan Förberg
+// SPDX-License-Identifier: MIT
+
+//! Support for running as `mount.seafuse`, the helper mount(8) calls for filesystems of type
+//! `fuse.seafuse`. Such entries in /etc/fstab look like
+//!
+//!     /srv/seafile/storage#<uuid>  /mnt/docs  fuse.seafuse  ro,allow_other  0  0
+//!
+//! Leaving out `#<uuid>` mounts every library of the storage root.
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// Name the helper is installed under, usually as a link to seafuse
+pub const HELPER_NAME: &str = "mount.seafuse";
+
+/// Options taking no value, which map onto the flag of the same name
+const FLAG_OPTIONS: &[&str] = &[
+    "allow_other",
+    "allow_root",
+    "auto_unmount",
+    "default_permissions",
+    "follow",
+    "statfs_blocks",
+    "control_dir",
+    "case_insensitive",
+];
+
+/// Options taking a value, which map onto the option of the same name
+const VALUE_OPTIONS: &[&str] = &[
+    "fsname",
+    "subtype",
+    "entry_timeout",
+    "attr_timeout",
+    "pidfile",
+    "threads",
+    "commit",
+    "follow_interval",
+    "fs_cache_size",
+    "max_open_files",
+    "readahead",
+    "block_cache_size",
+    "normalize",
+];
+
+/// Options which only apply to a single library, and so need `#<uuid>` in the source
+const SINGLE_LIBRARY_OPTIONS: &[&str] = &["commit", "follow", "follow_interval", "control_dir"];
+
+/// Options meant for mount(8) or systemd, or which make no difference to a read-only
+/// filesystem without devices or setuid files
+const IGNORED_OPTIONS: &[&str] = &[
+    "ro",
+    "defaults",
+    "auto",
+    "noauto",
+    "user",
+    "nouser",
+    "users",
+    "owner",
+    "group",
+    "nofail",
+    "_netdev",
+    "dev",
+    "nodev",
+    "suid",
+    "nosuid",
+    "exec",
+    "noexec",
+    "atime",
+    "noatime",
+    "relatime",
+    "strictatime",
+    "nodiratime",
+    "sync",
+    "async",
+    "dirsync",
+];
+
+/// Whether the program was started as the mount helper
+pub fn is_helper(argv0: &OsStr) -> bool {
+    Path::new(argv0).file_name() == Some(HELPER_NAME.as_ref())
+}
+
+/// Translate the arguments mount(8) passes to a helper, `<source> <target> [-sfnv] [-o options]
+/// [-t type]`, into arguments for seafuse. Returns None if there is nothing to do.
+pub fn translate_args(argv: &[String]) -> Result<Option<Vec<String>>, String> {
+    let mut positional = vec![];
+    let mut options = vec![];
+    let mut verbose = false;
+    let mut sloppy = false;
+    let mut fake = false;
+
+    let mut it = argv.iter().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "-o" => options.extend(
+                it.next()
+                    .ok_or("Missing argument to -o")?
+                    .split(',')
+                    .map(str::to_string),
+            ),
+            "-t" => {
+                it.next().ok_or("Missing argument to -t")?;
+            }
+            a if a.starts_with("-o") => options.extend(a[2..].split(',').map(str::to_string)),
+            a if a.starts_with('-') && a.len() > 1 => {
+                for c in a[1..].chars() {
+                    match c {
+                        'v' => verbose = true,
+                        's' => sloppy = true,
+                        'f' => fake = true,
+                        'n' => {}
+                        _ => return Err(format!("Unknown flag -{c}")),
+                    }
+                }
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    let [source, target] = &positional[..] else {
+        return Err(format!(
+            "Usage: {HELPER_NAME} <storage>[#<uuid>] <mountpoint> [-o options]"
+        ));
+    };
+
+    let mut args = vec!["seafuse".to_string()];
+    if verbose {
+        args.push("-v".into());
+    }
+
+    let single = match source.rsplit_once('#') {
+        Some((storage, uuid)) => {
+            args.extend(["mount".into(), storage.into(), uuid.into()]);
+            true
+        }
+        None => {
+            args.extend(["mount-all".into(), source.clone()]);
+            false
+        }
+    };
+    args.push(target.clone());
+
+    // mount(8) expects the helper to return once the filesystem is mounted
+    args.push("--daemon".into());
+
+    for o in options.iter().filter(|o| !o.is_empty()) {
+        let (key, value) = match o.split_once('=') {
+            Some((k, v)) => (k, Some(v)),
+            None => (o.as_str(), None),
+        };
+
+        if IGNORED_OPTIONS.contains(&key) || key.starts_with("x-") || key == "comment" {
+            continue;
+        }
+        if !single && SINGLE_LIBRARY_OPTIONS.contains(&key) {
+            return Err(format!(
+                "Mount option {key:?} needs a single library, as in <storage>#<uuid>"
+            ));
+        }
+
+        match value {
+            None if FLAG_OPTIONS.contains(&key) => args.push(flag(key)),
+            Some(v) if VALUE_OPTIONS.contains(&key) => args.extend([flag(key), v.to_string()]),
+            _ if key == "rw" => return Err("seafuse can only be mounted read-only".into()),
+            // Runs before logging is set up
+            _ if sloppy => eprintln!("{HELPER_NAME}: Ignoring unknown mount option {o:?}"),
+            _ => return Err(format!("Unknown mount option {o:?}")),
+        }
+    }
+
+    Ok((!fake).then_some(args))
+}
+
+fn flag(key: &str) -> String {
+    format!("--{}", key.replace('_', "-"))
+}
+
+#[test]
+fn test_translate_args() {
+    let argv = |s: &str| s.split(' ').map(str::to_string).collect::<Vec<_>>();
+
+    assert_eq!(
+        translate_args(&argv(
+            "/sbin/mount.seafuse /srv/storage#1234 /mnt -o rw,noauto,x-systemd.automount"
+        )),
+        Err("seafuse can only be mounted read-only".into())
+    );
+    assert_eq!(
+        translate_args(&argv(
+            "/sbin/mount.seafuse /srv/storage#1234 /mnt -n -o ro,noauto,x-systemd.automount,allow_other,commit=abcd"
+        ))
+        .unwrap()
+        .unwrap(),
+        argv("seafuse mount /srv/storage 1234 /mnt --daemon --allow-other --commit abcd")
+    );
+    assert_eq!(
+        translate_args(&argv(
+            "mount.seafuse /srv/storage /mnt -v -ofs_cache_size=10"
+        ))
+        .unwrap()
+        .unwrap(),
+        argv("seafuse -v mount-all /srv/storage /mnt --daemon --fs-cache-size 10")
+    );
+    assert!(translate_args(&argv("mount.seafuse /srv/storage /mnt -o bogus")).is_err());
+    assert!(translate_args(&argv("mount.seafuse /srv/storage /mnt -o follow")).is_err());
+    assert!(translate_args(&argv("mount.seafuse /srv/storage /mnt -s -o commit=abcd")).is_err());
+    assert!(translate_args(&argv("mount.seafuse /srv/storage /mnt -s -o bogus")).is_ok());
+    assert_eq!(
+        translate_args(&argv("mount.seafuse /srv/storage /mnt -f")),
+        Ok(None)
+    );
+    assert!(is_helper("/usr/sbin/mount.seafuse".as_ref()));
+    assert!(!is_helper("seafuse".as_ref()));
+}
@@ -1,8 +1,12 @@
 // Copyright 2025 Johan Förberg
 // SPDX-License-Identifier: MIT
 
+mod control;
 mod fuse;
+mod multi;
 mod repo;
 
+pub use control::*;
 pub use fuse::*;
+pub use multi::*;
 pub use repo::*;
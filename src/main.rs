@@ -3,17 +3,39 @@
 
 use chrono::{DateTime, Utc};
 use clap::Parser;
-use log::debug;
+use fuser::MountOption;
+use log::{error, info};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use simple_logger::SimpleLogger;
 use std::cmp::{max, min};
-use std::fs;
-use std::io;
+use std::env;
+use std::ffi::OsString;
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::thread;
 use std::time::{Duration, UNIX_EPOCH};
 
 use seafuse::*;
 
+mod daemon;
+mod export;
+mod extract;
+mod filter;
+mod helper;
+use daemon::Daemon;
+use export::{do_export, ExportArgs};
+use extract::{do_extract, ExtractArgs};
+
+// Exit codes, following sysexits(3). Usage errors are reported by clap with exit code 2.
+const EX_DATAERR: i32 = 65;
+const EX_NOINPUT: i32 = 66;
+const EX_SOFTWARE: i32 = 70;
+const EX_IOERR: i32 = 74;
+const EX_NOPERM: i32 = 77;
+
 #[derive(clap::Parser, Debug)]
 struct Args {
     #[clap(subcommand)]
@@ -32,11 +54,26 @@ enum Op {
 
         target: PathBuf,
 
-        #[arg(short = 'p', long)]
-        prefix: Option<PathBuf>,
+        #[command(flatten)]
+        extract: ExtractArgs,
+
+        #[command(flatten)]
+        tuning: TuningArgs,
+    },
+    /// Write the files of a library to a tar or zip archive
+    Export {
+        source: PathBuf,
+
+        uuid: String,
+
+        /// Only export this file or directory
+        path: Option<PathBuf>,
+
+        #[command(flatten)]
+        export: ExportArgs,
 
-        #[arg(short = 'n', long, default_value_t = false)]
-        dry_run: bool,
+        #[command(flatten)]
+        tuning: TuningArgs,
     },
     Mount {
         source: PathBuf,
@@ -44,6 +81,41 @@ enum Op {
         uuid: String,
 
         target: PathBuf,
+
+        #[command(flatten)]
+        tuning: TuningArgs,
+
+        #[command(flatten)]
+        mount: MountArgs,
+
+        /// Serve a .seafuse directory in the root of the mount, with information about the library
+        /// and a ctl file to switch between commits. This makes the mount writable.
+        #[arg(long, default_value_t = false)]
+        control_dir: bool,
+
+        /// Serve this commit instead of the newest one
+        #[arg(long, conflicts_with = "follow", value_parser = parse_commit_id)]
+        commit: Option<Sha1>,
+
+        /// Switch to new commits as they appear in the library. SIGHUP forces a check.
+        #[arg(long, default_value_t = false)]
+        follow: bool,
+
+        /// Seconds between checks for new commits with --follow
+        #[arg(long, default_value_t = 30)]
+        follow_interval: u64,
+    },
+    /// Mount every library of the storage root, each as a directory named after the library
+    MountAll {
+        source: PathBuf,
+
+        target: PathBuf,
+
+        #[command(flatten)]
+        tuning: TuningArgs,
+
+        #[command(flatten)]
+        mount: MountArgs,
     },
     Stats {
         source: PathBuf,
@@ -52,15 +124,181 @@ enum Op {
     },
 }
 
-#[derive(Debug, PartialEq)]
-enum PrefixMatch {
-    Yes,
-    No,
-    Continue,
+/// Options for the caches of each library
+#[derive(Debug, Clone, clap::Args)]
+struct TuningArgs {
+    /// Number of parsed fs objects to keep in memory
+    #[arg(long, default_value_t = DEFAULT_FS_CACHE_SIZE)]
+    fs_cache_size: usize,
+
+    /// Number of block files to keep open between reads. Reads in progress may briefly hold a
+    /// few more.
+    #[arg(long, default_value_t = DEFAULT_MAX_OPEN_FILES)]
+    max_open_files: usize,
+
+    /// Number of blocks to read ahead of sequential readers (0 disables readahead). Each library
+    /// then keeps up to --block-cache-size prefetched blocks in memory, and as blocks are up to
+    /// 8 MiB, the default cache may take 128 MiB per opened library.
+    #[arg(long, default_value_t = DEFAULT_READAHEAD)]
+    readahead: usize,
+
+    /// Number of prefetched blocks to keep in memory, for each library, with --readahead
+    #[arg(long, default_value_t = DEFAULT_BLOCK_CACHE_SIZE)]
+    block_cache_size: usize,
+}
+
+impl TuningArgs {
+    fn apply(&self, lib: Library) -> Library {
+        lib.with_fs_cache_size(self.fs_cache_size)
+            .with_max_open_files(self.max_open_files)
+            .with_readahead(self.readahead, self.block_cache_size)
+    }
+}
+
+/// Options for how a filesystem is mounted and served
+#[derive(Debug, Clone, clap::Args)]
+struct MountArgs {
+    /// Number of worker threads serving filesystem requests (0 serves them all on the
+    /// session thread)
+    #[arg(short = 'j', long, default_value_t = 4)]
+    threads: usize,
+
+    /// Make statfs report the space taken by blocks instead of the size of the served files
+    #[arg(long, default_value_t = false)]
+    statfs_blocks: bool,
+
+    /// Allow all users to access the mount
+    #[arg(long, default_value_t = false)]
+    allow_other: bool,
+
+    /// Allow root to access the mount, in addition to the user who mounted it
+    #[arg(long, default_value_t = false)]
+    allow_root: bool,
+
+    /// Unmount automatically if seafuse exits without unmounting
+    #[arg(long, default_value_t = false)]
+    auto_unmount: bool,
+
+    /// Let the kernel check file permissions
+    #[arg(long, default_value_t = false)]
+    default_permissions: bool,
+
+    /// Source shown in the mount table [default: seafuse#<library name>]
+    #[arg(long)]
+    fsname: Option<String>,
+
+    /// Filesystem subtype shown in the mount table
+    #[arg(long, default_value = "seafuse")]
+    subtype: String,
+
+    /// Seconds the kernel may cache name lookups for [default: forever]
+    #[arg(long)]
+    entry_timeout: Option<u64>,
+
+    /// Seconds the kernel may cache file attributes for [default: forever]
+    #[arg(long)]
+    attr_timeout: Option<u64>,
+
+    /// Run in the background once mounted
+    #[arg(long, default_value_t = false)]
+    daemon: bool,
+
+    /// Write the process id to this file while mounted
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+
+    /// Look up names regardless of case
+    #[arg(long, default_value_t = false)]
+    case_insensitive: bool,
+
+    /// Look up names regardless of Unicode normalization, comparing them in this form (nfc, nfd
+    /// or none)
+    #[arg(long, default_value = "none")]
+    normalize: Normalization,
+}
+
+impl MountArgs {
+    /// Go into the background if asked to. Must be called before any threads are started.
+    fn start(&self) -> Daemon {
+        if self.daemon {
+            Daemon::background(self.pidfile.clone())
+        } else {
+            Daemon::foreground(self.pidfile.clone())
+        }
+    }
+
+    fn name_matching(&self) -> NameMatching {
+        NameMatching {
+            case_insensitive: self.case_insensitive,
+            normalize: self.normalize,
+        }
+    }
+
+    fn statfs_source(&self) -> StatfsSource {
+        if self.statfs_blocks {
+            StatfsSource::Blocks
+        } else {
+            StatfsSource::Commit
+        }
+    }
+
+    /// Mount options, naming the filesystem `default_fsname` unless told otherwise. The mount is
+    /// read-only unless `writable`.
+    fn options(&self, default_fsname: &str, writable: bool) -> Vec<MountOption> {
+        let fsname = self.fsname.as_deref().unwrap_or(default_fsname);
+        let mut options = vec![
+            if writable {
+                MountOption::RW
+            } else {
+                MountOption::RO
+            },
+            // Options are separated by commas, so there can be none in the name
+            MountOption::FSName(fsname.replace(',', "_")),
+            MountOption::Subtype(self.subtype.clone()),
+        ];
+
+        for (set, option) in [
+            (self.allow_other, MountOption::AllowOther),
+            (self.allow_root, MountOption::AllowRoot),
+            (self.auto_unmount, MountOption::AutoUnmount),
+            (self.default_permissions, MountOption::DefaultPermissions),
+        ] {
+            if set {
+                options.push(option);
+            }
+        }
+
+        options
+    }
+
+    fn server<F: PreFilesystem + Send + Sync + 'static>(&self, fs: F) -> FuseServer<F> {
+        let ttl = |t: Option<u64>| t.map(Duration::from_secs).unwrap_or(INF_TTL);
+
+        FuseServer::new(fs, self.threads).with_ttl(ttl(self.entry_timeout), ttl(self.attr_timeout))
+    }
 }
 
 fn main() {
-    let args = Args::parse();
+    let argv: Vec<OsString> = env::args_os().collect();
+    let args = if argv.first().is_some_and(|a| helper::is_helper(a)) {
+        let argv = argv
+            .into_iter()
+            .map(|a| {
+                a.into_string()
+                    .map_err(|a| format!("Invalid argument {a:?}"))
+            })
+            .collect::<Result<Vec<String>, String>>();
+        match argv.and_then(|argv| helper::translate_args(&argv)) {
+            Ok(Some(args)) => Args::parse_from(args),
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("{}: {e}", helper::HELPER_NAME);
+                process::exit(1);
+            }
+        }
+    } else {
+        Args::parse_from(argv)
+    };
 
     let log_level = if args.verbose {
         log::LevelFilter::Debug
@@ -74,124 +312,181 @@ fn main() {
         .init()
         .unwrap();
 
-    match args.op {
+    let r = match args.op {
         Op::Extract {
             source,
             uuid,
             target,
-            prefix,
-            dry_run,
-        } => do_extract(
-            &source,
-            &uuid,
-            &target,
-            &prefix.unwrap_or("".into()),
-            dry_run,
-        ),
+            extract,
+            tuning,
+        } => Library::open(&source, &uuid)
+            .and_then(|lib| do_extract(&tuning.apply(lib), &target, &extract)),
+        Op::Export {
+            source,
+            uuid,
+            path,
+            export,
+            tuning,
+        } => Library::open(&source, &uuid)
+            .and_then(|lib| do_export(&tuning.apply(lib), path.as_deref(), &export)),
         Op::Mount {
             source,
             uuid,
             target,
-        } => do_mount(&source, &uuid, &target),
+            tuning,
+            mount,
+            control_dir,
+            commit,
+            follow,
+            follow_interval,
+        } => {
+            let daemon = mount.start();
+            let lib = match commit {
+                Some(id) => Library::open_for_commit(&source, &uuid, id),
+                None => Library::open(&source, &uuid),
+            };
+            lib.and_then(|lib| {
+                let lib = tuning.apply(lib).with_name_matching(mount.name_matching());
+                let follow_interval = follow.then_some(Duration::from_secs(follow_interval));
+                let fs = SeafFuse::new(lib)
+                    .with_statfs(mount.statfs_source())
+                    .with_control_dir(control_dir);
+                do_mount(fs, &target, &mount, follow_interval, daemon)
+            })
+        }
+        Op::MountAll {
+            source,
+            target,
+            tuning,
+            mount,
+        } => {
+            let daemon = mount.start();
+            find_libraries(&source).and_then(|libraries| {
+                let matching = mount.name_matching();
+                let fs = MultiFuse::new(libraries, move |lib| {
+                    tuning.apply(lib).with_name_matching(matching)
+                })
+                .with_statfs(mount.statfs_source());
+                do_mount_all(fs, &target, &mount, daemon)
+            })
+        }
         Op::Stats { source, uuid } => do_stats(&source, &uuid),
     };
-}
 
-fn do_extract(source: &Path, uuid: &str, target: &Path, prefix: &Path, dry_run: bool) {
-    let lib = Library::open(source, uuid).unwrap();
-    let mut file_counter = 0;
-    let mut dir_counter = 0;
-
-    fs::create_dir_all(target).expect("Failed to create target directory");
-
-    let mut it = lib.fs_iterator();
-    while let Some(r) = it.next() {
-        let (p, de, fs) = r.expect("Failed to get fs entry");
-        let full_path = p.join(&de.name);
-        let target_path = target.join(&full_path);
-
-        match match_prefix(prefix, &full_path) {
-            PrefixMatch::Yes => {}
-            PrefixMatch::No => {
-                debug!("Pruning directory {full_path:?}");
-                it.prune();
-                continue;
-            }
-            PrefixMatch::Continue => {
-                debug!("Ignoring directory {full_path:?}");
-                continue;
-            }
-        }
+    if let Err(e) = r {
+        eprintln!("seafuse: {}", e.report());
+        process::exit(exit_code(&e));
+    }
+}
 
-        debug!("Extracting {}: {}", fs.type_name(), target_path.display());
+fn parse_commit_id(s: &str) -> Result<Sha1, String> {
+    Sha1::parse(s)
+        .filter(|_| s.len() == 40)
+        .ok_or_else(|| format!("Invalid commit id {s:?}"))
+}
 
-        if dry_run {
-            continue;
+fn exit_code(e: &SeafError) -> i32 {
+    match e {
+        SeafError::Context(_, e) => exit_code(e),
+        SeafError::NotFound(_) | SeafError::ObjectMissing(_) | SeafError::NoHeadCommit => {
+            EX_NOINPUT
         }
-
-        if let Some(parent) = target_path.parent() {
-            fs::create_dir_all(parent).unwrap();
+        SeafError::CorruptObject(..) | SeafError::ParseJson(..) | SeafError::WrongFsType => {
+            EX_DATAERR
         }
+        SeafError::Encrypted(_) => EX_NOPERM,
+        SeafError::IO(_, e) => match e.kind() {
+            ErrorKind::NotFound => EX_NOINPUT,
+            ErrorKind::PermissionDenied => EX_NOPERM,
+            _ => EX_IOERR,
+        },
+        SeafError::WalkDir(_) | SeafError::BlockUnreadable(..) => EX_IOERR,
+        SeafError::NotImpl => EX_SOFTWARE,
+    }
+}
 
-        match fs {
-            FsJson::Dir(_) => {
-                let r = match fs::create_dir(&target_path) {
-                    Err(e) => {
-                        if e.kind() == ErrorKind::AlreadyExists {
-                            Ok(())
-                        } else {
-                            Err(e)
-                        }
-                    }
-                    x => x,
-                };
-
-                r.unwrap_or_else(|e| {
-                    panic!("Failed to create new directory {:?}: {:?}", &target_path, e)
-                });
-
-                dir_counter += 1;
-            }
-            FsJson::File(f) => {
-                let mut w = fs::File::create(&target_path).unwrap_or_else(|e| {
-                    panic!("Failed to create file {:?}: {:?}", &target_path, e)
-                });
-                let mut r = lib
-                    .file_reader(&f)
-                    .unwrap_or_else(|e| panic!("Failed to open file ({f:?}) for reading: {e:?}"));
+fn do_mount(
+    fs: SeafFuse,
+    target: &Path,
+    mount: &MountArgs,
+    follow_interval: Option<Duration>,
+    daemon: Daemon,
+) -> Result<(), SeafError> {
+    let fsname = format!("seafuse#{}", fs.commit().repo_name);
+    let writable = fs.has_control_dir();
+    let server = mount.server(fs);
+    let fs = server.fs();
+    let session = fuser::Session::new(server, target, &mount.options(&fsname, writable))
+        .map_err(|e| SeafError::IO(target.into(), e).context("Failed to mount"))?;
+
+    fs.set_notifier(session.notifier());
+
+    let wakeup = follow_interval.map(|interval| {
+        let (wakeup_tx, wakeup_rx) = mpsc::channel();
+        thread::spawn(move || follow_head(fs, interval, wakeup_rx));
+        wakeup_tx
+    });
+
+    run_session(session, target, wakeup, daemon)
+}
 
-                io::copy(&mut r, &mut w).expect("Failed to copy data to new file");
+fn do_mount_all(
+    fs: MultiFuse,
+    target: &Path,
+    mount: &MountArgs,
+    daemon: Daemon,
+) -> Result<(), SeafError> {
+    println!("Mounting {} libraries", fs.names().len());
 
-                file_counter += 1;
-            }
-        }
-    }
+    let server = mount.server(fs);
+    let session = fuser::Session::new(server, target, &mount.options("seafuse", false))
+        .map_err(|e| SeafError::IO(target.into(), e).context("Failed to mount"))?;
 
-    println!("Extracted {dir_counter} directories, {file_counter} files");
+    run_session(session, target, None, daemon)
 }
 
-fn match_prefix(pref: &Path, path: &Path) -> PrefixMatch {
-    let ret = if pref.as_os_str().is_empty() || path.starts_with(pref) {
-        PrefixMatch::Yes
-    } else if pref.starts_with(path) {
-        PrefixMatch::Continue
-    } else {
-        PrefixMatch::No
-    };
+/// Serve a mounted filesystem until it is unmounted, or until SIGTERM or SIGINT arrives. SIGHUP
+/// sends a message on `wakeup`, if there is a followed library to refresh, and is ignored otherwise.
+fn run_session<F>(
+    mut session: fuser::Session<FuseServer<F>>,
+    target: &Path,
+    wakeup: Option<mpsc::Sender<()>>,
+    mut daemon: Daemon,
+) -> Result<(), SeafError>
+where
+    F: PreFilesystem + Send + Sync + 'static,
+{
+    let mut hangups = Signals::new([SIGHUP]).expect("Failed to install SIGHUP handler");
+
+    thread::spawn(move || {
+        for _ in hangups.forever() {
+            if wakeup.as_ref().is_none_or(|tx| tx.send(()).is_err()) {
+                info!("Ignoring SIGHUP, as no library is followed for new commits");
+            }
+        }
+    });
 
-    ret
-}
+    let mut unmounter = session.unmount_callable();
+    let mut signals = Signals::new([SIGTERM, SIGINT]).expect("Failed to install SIGTERM handler");
+
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Unmounting on signal {signal}");
+            if let Err(e) = unmounter.unmount() {
+                error!("Failed to unmount: {e}");
+            }
+        }
+    });
 
-fn do_mount(source: &Path, uuid: &str, target: &Path) {
-    let lib = Library::open(source, uuid).unwrap();
-    let fs = SeafFuse::new(lib.clone());
+    daemon.ready()?;
 
-    fuser::mount2(fs, target, &[])
-        .unwrap_or_else(|e| panic!("Failed to mount {:?}: {:?}", &target, e));
+    session
+        .run()
+        .map_err(|e| SeafError::IO(target.into(), e).context("Failed to serve"))
 }
 
-fn do_stats(source: &Path, uuid: &str) {
-    let lib = Library::open(source, uuid).unwrap();
+fn do_stats(source: &Path, uuid: &str) -> Result<(), SeafError> {
+    let lib = Library::open(source, uuid)?;
     let head_commit_id = lib.head_commit.commit_id;
     let repo_name = &lib.head_commit.repo_name;
     println!("Head commit: {head_commit_id}");
@@ -201,7 +496,8 @@ fn do_stats(source: &Path, uuid: &str) {
     let mut min_ctime = u64::MAX;
     let mut max_ctime = 0;
 
-    for c in lib.commit_iterator().map(|c| c.unwrap()) {
+    for c in lib.commit_iterator() {
+        let c = c?;
         commit_count += 1;
         min_ctime = min(min_ctime, c.ctime);
         max_ctime = max(max_ctime, c.ctime);
@@ -219,7 +515,8 @@ fn do_stats(source: &Path, uuid: &str) {
     let mut max_blocks_in_file = 0;
     let mut max_files_in_dir = 0;
 
-    for (_p, _de, fs) in lib.fs_iterator().map(|fs| fs.unwrap()) {
+    for r in lib.fs_iterator() {
+        let (_p, _de, fs) = r?;
         match fs {
             FsJson::File(f) => {
                 file_count += 1;
@@ -236,6 +533,8 @@ fn do_stats(source: &Path, uuid: &str) {
     println!("Directory count: {dir_count}");
     println!("Max blocks in a file: {max_blocks_in_file}");
     println!("Max files in a directory: {max_files_in_dir}");
+
+    Ok(())
 }
 
 fn format_unix_time(t: u64) -> String {
@@ -246,39 +545,43 @@ fn format_unix_time(t: u64) -> String {
 }
 
 #[test]
-fn test_prefix_examples() {
-    assert_eq!(
-        match_prefix(Path::new(""), Path::new("test/test2")),
-        PrefixMatch::Yes
-    );
-    assert_eq!(match_prefix(Path::new(""), Path::new("")), PrefixMatch::Yes);
-    assert_eq!(
-        match_prefix(Path::new("test"), Path::new("")),
-        PrefixMatch::Continue
-    );
+fn test_mount_options() {
+    let args = Args::try_parse_from([
+        "seafuse",
+        "mount",
+        "storage",
+        "uuid",
+        "mnt",
+        "--allow-other",
+        "--fsname",
+        "docs,old",
+    ])
+    .unwrap();
+    let Op::Mount { mount, .. } = args.op else {
+        panic!("Not a mount: {:?}", args.op);
+    };
 
     assert_eq!(
-        match_prefix(Path::new("test"), Path::new("test/test2")),
-        PrefixMatch::Yes
-    );
-    assert_eq!(
-        match_prefix(Path::new("test2"), Path::new("test")),
-        PrefixMatch::No
-    );
-    assert_eq!(
-        match_prefix(Path::new("test2"), Path::new("test/test2")),
-        PrefixMatch::No
-    );
-    assert_eq!(
-        match_prefix(Path::new("test/test2"), Path::new("test")),
-        PrefixMatch::Continue
-    );
-    assert_eq!(
-        match_prefix(Path::new("test/test2"), Path::new("test/test2")),
-        PrefixMatch::Yes
+        mount.options("seafuse#Docs", false),
+        [
+            MountOption::RO,
+            MountOption::FSName("docs_old".into()),
+            MountOption::Subtype("seafuse".into()),
+            MountOption::AllowOther,
+        ]
     );
+}
+
+#[test]
+fn test_exit_codes() {
+    let missing = std::io::Error::from(ErrorKind::NotFound);
+
+    assert_eq!(exit_code(&SeafError::IO("x".into(), missing)), EX_NOINPUT);
+    assert_eq!(exit_code(&SeafError::Encrypted("x".into())), EX_NOPERM);
     assert_eq!(
-        match_prefix(Path::new("test/test2"), Path::new("test/test")),
-        PrefixMatch::No
+        exit_code(&SeafError::WrongFsType.context("Failed to read x")),
+        EX_DATAERR
     );
+    assert!(parse_commit_id("a47ac095b65fdfb64dd751570219036202577f0c").is_ok());
+    assert!(parse_commit_id("a47ac095").is_err());
 }
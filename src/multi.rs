@@ -0,0 +1,446 @@
+// Copyright 2025 Johan Förberg
+// SPDX-License-Identifier: MIT
+
+use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
+use libc::{c_int, EACCES, EBADF, EISDIR, ENODATA, ENOENT, ESTALE};
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::fuse::*;
+use crate::repo::*;
+
+/// Number of low bits of inode numbers and file handles which belong to the library. The high
+/// bits hold the slot of the library, slot 0 being the top-level directory itself.
+const SLOT_SHIFT: u32 = 40;
+
+const LOCAL_MASK: u64 = (1 << SLOT_SHIFT) - 1;
+
+/// Function applied to each library as it is opened, to set up caches and the like
+type Configure = dyn Fn(Library) -> Library + Send + Sync;
+
+/// Every library of a storage root mounted at once, each as a directory at the top level. A
+/// library is only opened once something inside it is accessed, so mounting a storage root with
+/// hundreds of libraries is cheap.
+pub struct MultiFuse {
+    slots: Vec<Slot>,
+
+    /// Index into `slots` of each top-level directory name
+    by_name: HashMap<OsString, usize>,
+
+    /// Called on each library as it is opened
+    configure: Box<Configure>,
+
+    /// Snapshots of currently open directories, indexed by file handle
+    open_dir_table: RwLock<HashMap<u64, OpenDir>>,
+
+    /// The next directory handle to be used
+    dir_handle_counter: AtomicU64,
+
+    /// What statfs reports on, for each library as it is opened
+    statfs_source: StatfsSource,
+}
+
+struct OpenDir {
+    /// Index of the library the directory belongs to, plus one, or 0 for the top level
+    slot: usize,
+    entries: Arc<Vec<Dentry>>,
+}
+
+struct Slot {
+    info: LibraryInfo,
+
+    /// Name of the top-level directory of the library
+    name: OsString,
+
+    /// The mounted library, once opened
+    fs: Mutex<Option<Arc<SeafFuse>>>,
+}
+
+impl MultiFuse {
+    /// Serve `libraries`, passing each through `configure` when it is opened
+    pub fn new<F>(libraries: Vec<LibraryInfo>, configure: F) -> MultiFuse
+    where
+        F: Fn(Library) -> Library + Send + Sync + 'static,
+    {
+        let names = dir_names(&libraries);
+        let slots: Vec<Slot> = libraries
+            .into_iter()
+            .zip(names)
+            .map(|(info, name)| Slot {
+                info,
+                name,
+                fs: Mutex::new(None),
+            })
+            .collect();
+        let by_name = slots
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.name.clone(), i))
+            .collect();
+
+        MultiFuse {
+            slots,
+            by_name,
+            configure: Box::new(configure),
+            open_dir_table: RwLock::new(HashMap::new()),
+            dir_handle_counter: AtomicU64::new(1),
+            statfs_source: StatfsSource::default(),
+        }
+    }
+
+    /// Choose what statfs reports on
+    pub fn with_statfs(mut self, source: StatfsSource) -> MultiFuse {
+        self.statfs_source = source;
+        self
+    }
+
+    /// Names of the top-level directories, in order
+    pub fn names(&self) -> Vec<OsString> {
+        self.slots.iter().map(|s| s.name.clone()).collect()
+    }
+
+    /// Number of libraries which have been opened so far
+    pub fn opened_count(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|s| s.fs.lock().unwrap().is_some())
+            .count()
+    }
+
+    /// Get the index and slot of the library which an inode or file handle belongs to
+    fn slot_of(&self, n: u64) -> Result<(usize, &Slot), c_int> {
+        let i = ((n >> SLOT_SHIFT) as usize).wrapping_sub(1);
+        match self.slots.get(i) {
+            Some(slot) => Ok((i, slot)),
+            None => {
+                error!("Inode or handle {n} does not belong to any library");
+                Err(ESTALE)
+            }
+        }
+    }
+
+    /// Get the mounted library of `slot`, opening it if this is the first access
+    fn open_slot(&self, slot: &Slot) -> Result<Arc<SeafFuse>, c_int> {
+        if slot.info.recent_commit.encrypted {
+            debug!("Refusing access to encrypted library {:?}", slot.name);
+            return Err(EACCES);
+        }
+
+        let mut fs = slot.fs.lock().unwrap();
+        if let Some(fs) = &*fs {
+            return Ok(fs.clone());
+        }
+
+        let lib = slot.info.open().map_err(|e| {
+            let errno = e.errno();
+            error!(
+                "Failed to open library {}: {} (errno {errno})",
+                slot.info.location.uuid,
+                e.report()
+            );
+            errno
+        })?;
+
+        info!("Opened library {:?}", slot.name);
+
+        let opened = Arc::new(SeafFuse::new((self.configure)(lib)).with_statfs(self.statfs_source));
+        *fs = Some(opened.clone());
+        Ok(opened)
+    }
+
+    /// Get the mounted library of `slot` only if it has already been opened
+    fn opened_slot(&self, slot: &Slot) -> Option<Arc<SeafFuse>> {
+        slot.fs.lock().unwrap().clone()
+    }
+
+    /// Attributes of the top-level directory of the library in slot `i`. These are made up, so
+    /// that listing the top level does not have to open every library.
+    fn slot_attr(&self, i: usize) -> FileAttr {
+        let mut attr = make_attr(global(i, FUSE_ROOT_ID), &FsJson::Dir(EMPTY_DIR_JSON));
+        if self.slots[i].info.recent_commit.encrypted {
+            attr.perm = 0o000;
+        }
+        attr
+    }
+
+    fn root_attr(&self) -> FileAttr {
+        make_attr(FUSE_ROOT_ID, &FsJson::Dir(EMPTY_DIR_JSON))
+    }
+
+    fn insert_dir_handle(&self, slot: usize, entries: Arc<Vec<Dentry>>) -> u64 {
+        let mut table = self.open_dir_table.write().unwrap();
+        let mut fh = self.dir_handle_counter.fetch_add(1, Ordering::Relaxed);
+        while table.contains_key(&fh) {
+            fh = self.dir_handle_counter.fetch_add(1, Ordering::Relaxed);
+        }
+
+        table.insert(fh, OpenDir { slot, entries });
+        fh
+    }
+}
+
+impl PreFilesystem for MultiFuse {
+    fn do_lookup(&self, parent_ino: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        if parent_ino == FUSE_ROOT_ID {
+            return match self.by_name.get(name) {
+                Some(&i) => Ok(self.slot_attr(i)),
+                None => Err(ENOENT),
+            };
+        }
+
+        let (i, slot) = self.slot_of(parent_ino)?;
+        let attr = self.open_slot(slot)?.do_lookup(local(parent_ino), name)?;
+        Ok(globalize(i, attr))
+    }
+
+    fn do_getattr(&self, ino: u64) -> Result<FileAttr, c_int> {
+        if ino == FUSE_ROOT_ID {
+            return Ok(self.root_attr());
+        }
+
+        let (i, slot) = self.slot_of(ino)?;
+        if local(ino) == FUSE_ROOT_ID {
+            return Ok(self.slot_attr(i));
+        }
+
+        let attr = self.open_slot(slot)?.do_getattr(local(ino))?;
+        Ok(globalize(i, attr))
+    }
+
+    fn do_readdir(&self, ino: u64) -> Result<Vec<Dentry>, c_int> {
+        if ino == FUSE_ROOT_ID {
+            return Ok(self
+                .slots
+                .iter()
+                .enumerate()
+                .map(|(i, s)| Dentry {
+                    // Top-level directories are not counted by id, see do_ref_dentry
+                    id: Sha1::default(),
+                    ino: global(i, FUSE_ROOT_ID),
+                    kind: FileType::Directory,
+                    name: s.name.clone(),
+                    attr: self.slot_attr(i),
+                    modifier: None,
+                })
+                .collect());
+        }
+
+        let (i, slot) = self.slot_of(ino)?;
+        let entries = self.open_slot(slot)?.do_readdir(local(ino))?;
+
+        Ok(entries
+            .into_iter()
+            .map(|d| {
+                let attr = globalize(i, d.attr);
+                Dentry {
+                    ino: attr.ino,
+                    attr,
+                    ..d
+                }
+            })
+            .collect())
+    }
+
+    fn do_opendir(&self, ino: u64) -> Result<u64, c_int> {
+        let slot = if ino == FUSE_ROOT_ID {
+            0
+        } else {
+            self.slot_of(ino)?.0 + 1
+        };
+        let entries = Arc::new(self.do_readdir(ino)?);
+        let count = entries.len();
+        let fh = self.insert_dir_handle(slot, entries);
+
+        debug!("Open dir fh={fh} ino={ino} entries={count}");
+
+        Ok(fh)
+    }
+
+    fn do_dir_entries(&self, fh: u64) -> Result<Arc<Vec<Dentry>>, c_int> {
+        match self.open_dir_table.read().unwrap().get(&fh) {
+            Some(od) => Ok(od.entries.clone()),
+            None => {
+                error!("Bad dir handle {fh}");
+                Err(EBADF)
+            }
+        }
+    }
+
+    fn do_releasedir(&self, fh: u64) -> Result<(), c_int> {
+        debug!("Close dir fh={fh}");
+
+        match self.open_dir_table.write().unwrap().remove(&fh) {
+            Some(_) => Ok(()),
+            None => {
+                error!("Invalid dir handle {fh}");
+                Err(EBADF)
+            }
+        }
+    }
+
+    fn do_open(&self, ino: u64, flags: i32) -> Result<u64, c_int> {
+        if ino == FUSE_ROOT_ID {
+            return Err(EISDIR);
+        }
+
+        let (i, slot) = self.slot_of(ino)?;
+        let fh = self.open_slot(slot)?.do_open(local(ino), flags)?;
+        Ok(global(i, fh))
+    }
+
+    fn do_release(&self, fh: u64) -> Result<(), c_int> {
+        let (_, slot) = self.slot_of(fh).map_err(|_| EBADF)?;
+        match self.opened_slot(slot) {
+            Some(fs) => fs.do_release(local(fh)),
+            None => Err(EBADF),
+        }
+    }
+
+    fn do_read(&self, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let (_, slot) = self.slot_of(fh).map_err(|_| EBADF)?;
+        match self.opened_slot(slot) {
+            Some(fs) => fs.do_read(local(fh), offset, size),
+            None => Err(EBADF),
+        }
+    }
+
+    fn do_write(&self, fh: u64, data: &[u8]) -> Result<u32, c_int> {
+        let (_, slot) = self.slot_of(fh).map_err(|_| EBADF)?;
+        match self.opened_slot(slot) {
+            Some(fs) => fs.do_write(local(fh), data),
+            None => Err(EBADF),
+        }
+    }
+
+    fn do_direct_io(&self, ino: u64) -> bool {
+        let slot = self
+            .slot_of(ino)
+            .ok()
+            .and_then(|(_, s)| self.opened_slot(s));
+        slot.is_some_and(|fs| fs.do_direct_io(local(ino)))
+    }
+
+    fn do_forget(&self, ino: u64, nlookup: u64) {
+        if ino == FUSE_ROOT_ID || local(ino) == FUSE_ROOT_ID {
+            return;
+        }
+
+        match self
+            .slot_of(ino)
+            .ok()
+            .and_then(|(_, s)| self.opened_slot(s))
+        {
+            Some(fs) => fs.do_forget(local(ino), nlookup),
+            None => debug!("Forget of unknown inode {ino}"),
+        }
+    }
+
+    fn do_statfs(&self) -> Result<UsageStats, c_int> {
+        // Only libraries which have been opened count, as going through all of them could take
+        // a very long time
+        let mut total = UsageStats::default();
+        for slot in &self.slots {
+            if let Some(fs) = self.opened_slot(slot) {
+                total = total + fs.do_statfs()?;
+            }
+        }
+
+        Ok(total)
+    }
+
+    fn do_getxattr(&self, ino: u64, name: &OsStr) -> Result<Vec<u8>, c_int> {
+        if ino == FUSE_ROOT_ID {
+            return Err(ENODATA);
+        }
+
+        let (_, slot) = self.slot_of(ino)?;
+        self.open_slot(slot)?.do_getxattr(local(ino), name)
+    }
+
+    fn do_listxattr(&self, ino: u64) -> Result<Vec<u8>, c_int> {
+        if ino == FUSE_ROOT_ID {
+            return Ok(vec![]);
+        }
+
+        let (_, slot) = self.slot_of(ino)?;
+        self.open_slot(slot)?.do_listxattr(local(ino))
+    }
+
+    fn do_ref_dentry(&self, fh: u64, d: &Dentry) -> FileAttr {
+        let slot = self
+            .open_dir_table
+            .read()
+            .unwrap()
+            .get(&fh)
+            .map(|od| od.slot);
+        let fs = slot
+            .and_then(|s| s.checked_sub(1))
+            .and_then(|i| Some((i, self.opened_slot(&self.slots[i])?)));
+
+        // The top-level directories are never evicted, so there is nothing to count
+        match fs {
+            Some((i, fs)) => globalize(i, fs.do_ref_dentry(fh, d)),
+            None => d.attr,
+        }
+    }
+
+    fn do_destroy(&self) {
+        for s in &self.slots {
+            if let Some(fs) = self.opened_slot(s) {
+                debug!("Library {:?}:", s.name);
+                fs.do_destroy();
+            }
+        }
+    }
+}
+
+/// Global number of the inode or file handle `n` of the library in slot `i`
+fn global(i: usize, n: u64) -> u64 {
+    ((i as u64 + 1) << SLOT_SHIFT) | n
+}
+
+/// Number of the inode or file handle `n` within its library
+fn local(n: u64) -> u64 {
+    n & LOCAL_MASK
+}
+
+/// Attributes from the library in slot `i`, with the inode number made global
+fn globalize(i: usize, attr: FileAttr) -> FileAttr {
+    FileAttr {
+        ino: global(i, attr.ino),
+        ..attr
+    }
+}
+
+/// Top-level directory names for `libraries`. Libraries are named after themselves, unless the
+/// name is taken by another library, in which case the start of the UUID is added.
+fn dir_names(libraries: &[LibraryInfo]) -> Vec<OsString> {
+    let base: Vec<String> = libraries
+        .iter()
+        .map(
+            |l| match l.recent_commit.repo_name.replace('/', "_").as_str() {
+                "" | "." | ".." => l.location.uuid.clone(),
+                name => name.to_string(),
+            },
+        )
+        .collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for name in &base {
+        *counts.entry(name).or_default() += 1;
+    }
+
+    base.iter()
+        .zip(libraries)
+        .map(|(name, l)| {
+            if counts[name.as_str()] > 1 {
+                let short: String = l.location.uuid.chars().take(8).collect();
+                OsString::from(format!("{name} ({short})"))
+            } else {
+                OsString::from(name)
+            }
+        })
+        .collect()
+}
@@ -2,17 +2,28 @@
 // SPDX-License-Identifier: MIT
 
 use flate2::read::ZlibDecoder;
-use serde::{Deserialize, Deserializer};
+use libc::{c_int, EACCES, EIO, ELOOP, ENAMETOOLONG, ENOENT, ENOSYS, ENOTDIR};
+use log::{debug, error, warn};
+use lru::LruCache;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::error::Error;
 use std::{
     cmp::min,
+    collections::{HashMap, HashSet},
     fmt,
     fmt::Debug,
     fmt::Display,
     fs, io,
     io::{Read, Seek, SeekFrom},
-    path::{Path, PathBuf},
-    sync::Arc,
+    num::NonZeroUsize,
+    os::unix::fs::FileExt,
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, Mutex},
+    time::SystemTime,
 };
+use threadpool::ThreadPool;
+use unicode_normalization::UnicodeNormalization;
 use walkdir::WalkDir;
 
 #[derive(Debug, Clone)]
@@ -21,10 +32,95 @@ pub struct LibraryLocation {
     pub uuid: String,
 }
 
+/// Default number of parsed fs objects kept in memory by each library
+pub const DEFAULT_FS_CACHE_SIZE: usize = 4096;
+
+/// Default number of block files each library keeps open between reads
+pub const DEFAULT_MAX_OPEN_FILES: usize = 256;
+
+/// Number of files whose block layout each library remembers between opens
+const BLOCK_LAYOUT_CACHE_SIZE: usize = 1024;
+
+/// Number of directories whose name index each library keeps in memory
+const DIR_INDEX_CACHE_SIZE: usize = 256;
+
+/// Default number of blocks to read ahead of a sequential reader. Readahead is off by default, as
+/// the prefetched blocks of every opened library are kept in memory.
+pub const DEFAULT_READAHEAD: usize = 0;
+
+/// Default number of prefetched blocks kept in memory
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 16;
+
+/// Number of background threads reading blocks ahead
+const PREFETCH_THREADS: usize = 2;
+
+/// Number of reads in a row, each starting where the previous one ended, after which readahead
+/// kicks in
+const SEQUENTIAL_READS: u32 = 1;
+
+/// A library found in a storage root. Finding the head commit means reading every commit, so that
+/// is left until the library is opened.
+#[derive(Debug, Clone)]
+pub struct LibraryInfo {
+    pub location: LibraryLocation,
+
+    /// The commit written last when the library was found, going by the times of the commit
+    /// files. Usually the head commit, and good enough for the name of the library and whether
+    /// it is encrypted.
+    pub recent_commit: CommitJson,
+}
+
+impl LibraryInfo {
+    /// Open the library at its head commit as of now
+    pub fn open(&self) -> Result<Library, SeafError> {
+        Library::open(&self.location.repo_path, &self.location.uuid)
+    }
+}
+
+/// Find every library with at least one commit in the storage root `repo_path`. Libraries which
+/// cannot be read are skipped, so that one broken library does not hide all the others.
+pub fn find_libraries(repo_path: &Path) -> Result<Vec<LibraryInfo>, SeafError> {
+    let commits_path = repo_path.join("commits");
+    let entries =
+        fs::read_dir(&commits_path).map_err(|e| SeafError::IO(commits_path.clone(), e))?;
+    let mut libraries = vec![];
+
+    for e in entries {
+        let e = e.map_err(|e| SeafError::IO(commits_path.clone(), e))?;
+        let Some(uuid) = e.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        let location = LibraryLocation {
+            repo_path: repo_path.to_path_buf(),
+            uuid,
+        };
+
+        match find_recent_commit(&location) {
+            Ok(recent_commit) => libraries.push(LibraryInfo {
+                location,
+                recent_commit,
+            }),
+            Err(SeafError::NoHeadCommit) => debug!("Library {} has no commits", location.uuid),
+            Err(e) => error!("Failed to read library {}: {}", location.uuid, e.report()),
+        }
+    }
+
+    libraries.sort_by(|a, b| a.location.uuid.cmp(&b.location.uuid));
+
+    Ok(libraries)
+}
+
 #[derive(Debug, Clone)]
 pub struct Library {
     pub location: Arc<LibraryLocation>,
     pub head_commit: CommitJson,
+    fs_cache: Arc<ObjCache<FsJson>>,
+    block_files: Arc<ObjCache<fs::File>>,
+    block_layouts: Arc<ObjCache<BlockLayout>>,
+    block_cache: Arc<ObjCache<Vec<u8>>>,
+    prefetcher: Option<Arc<Prefetcher>>,
+    dir_indexes: Arc<ObjCache<DirIndex>>,
+    name_matching: NameMatching,
 }
 
 impl Library {
@@ -38,6 +134,13 @@ impl Library {
         Ok(Library {
             location,
             head_commit,
+            fs_cache: Arc::new(ObjCache::new(DEFAULT_FS_CACHE_SIZE)),
+            block_files: Arc::new(ObjCache::new(DEFAULT_MAX_OPEN_FILES)),
+            block_layouts: Arc::new(ObjCache::new(BLOCK_LAYOUT_CACHE_SIZE)),
+            block_cache: Arc::new(ObjCache::new(0)),
+            prefetcher: None,
+            dir_indexes: Arc::new(ObjCache::new(DIR_INDEX_CACHE_SIZE)),
+            name_matching: NameMatching::default(),
         })
     }
 
@@ -55,56 +158,524 @@ impl Library {
         Ok(Library {
             location,
             head_commit,
+            fs_cache: Arc::new(ObjCache::new(DEFAULT_FS_CACHE_SIZE)),
+            block_files: Arc::new(ObjCache::new(DEFAULT_MAX_OPEN_FILES)),
+            block_layouts: Arc::new(ObjCache::new(BLOCK_LAYOUT_CACHE_SIZE)),
+            block_cache: Arc::new(ObjCache::new(0)),
+            prefetcher: None,
+            dir_indexes: Arc::new(ObjCache::new(DIR_INDEX_CACHE_SIZE)),
+            name_matching: NameMatching::default(),
         })
     }
 
+    /// Replace the fs object cache with one holding at most `size` objects. A size of zero
+    /// disables caching.
+    pub fn with_fs_cache_size(mut self, size: usize) -> Library {
+        self.fs_cache = Arc::new(ObjCache::new(size));
+        self
+    }
+
+    /// Keep at most `limit` block files open between reads. A limit of zero opens each block
+    /// anew for every read. This is a soft limit: a file evicted while a read is still using it
+    /// is only closed once that read is done, so each concurrent read may hold one more.
+    pub fn with_max_open_files(mut self, limit: usize) -> Library {
+        self.block_files = Arc::new(ObjCache::new(limit));
+        self
+    }
+
+    /// Read up to `window` blocks ahead of readers which go through a file sequentially, keeping
+    /// at most `cache_size` prefetched blocks in memory. Readahead is off if either is zero.
+    pub fn with_readahead(mut self, window: usize, cache_size: usize) -> Library {
+        if window > 0 && cache_size > 0 {
+            self.block_cache = Arc::new(ObjCache::new(cache_size));
+            self.prefetcher = Some(Arc::new(Prefetcher::new(window)));
+        } else {
+            self.block_cache = Arc::new(ObjCache::new(0));
+            self.prefetcher = None;
+        }
+        self
+    }
+
+    /// Match names looked up with `lookup_dirent` and `resolve_path` by `matching` instead of byte
+    /// for byte
+    pub fn with_name_matching(mut self, matching: NameMatching) -> Library {
+        self.name_matching = matching;
+        self.dir_indexes = Arc::new(ObjCache::new(DIR_INDEX_CACHE_SIZE));
+        self
+    }
+
+    /// Block until all blocks queued for prefetching have been read
+    pub fn wait_for_prefetch(&self) {
+        if let Some(p) = &self.prefetcher {
+            p.pool.join();
+        }
+    }
+
+    pub fn fs_cache_stats(&self) -> CacheStats {
+        self.fs_cache.stats()
+    }
+
+    pub fn block_cache_stats(&self) -> CacheStats {
+        self.block_cache.stats()
+    }
+
+    pub fn block_file_cache_stats(&self) -> CacheStats {
+        self.block_files.stats()
+    }
+
+    /// Look for the newest commit of the library, which may have changed since it was opened
+    pub fn latest_commit(&self) -> Result<CommitJson, SeafError> {
+        find_head_commit(&self.location)
+    }
+
     pub fn commit_iterator(&self) -> CommitIterator {
         commit_iterator(&self.location)
     }
 
+    /// Load the commit with the given id
+    pub fn commit_by_id(&self, id: Sha1) -> Result<CommitJson, SeafError> {
+        find_commit(&self.location, id)
+    }
+
+    /// Find the newest commit made no later than `ctime`, if any
+    pub fn commit_at(&self, ctime: u64) -> Result<Option<CommitJson>, SeafError> {
+        let mut found: Option<CommitJson> = None;
+
+        for c in self.commit_iterator() {
+            let c = c?;
+            if c.ctime <= ctime && found.as_ref().is_none_or(|f| c.ctime > f.ctime) {
+                found = Some(c);
+            }
+        }
+
+        Ok(found)
+    }
+
     pub fn load_fs(&self, id: Sha1) -> Result<FsJson, SeafError> {
+        Ok(self.load_fs_shared(id)?.as_ref().clone())
+    }
+
+    /// Like `load_fs`, but hands out the cached object itself instead of a copy
+    pub fn load_fs_shared(&self, id: Sha1) -> Result<Arc<FsJson>, SeafError> {
         if id == EMPTY_SHA1 {
-            Ok(FsJson::Dir(EMPTY_DIR_JSON))
-        } else {
-            parse_fs_json(&self.obj_path("fs", id))
+            return Ok(Arc::new(FsJson::Dir(EMPTY_DIR_JSON)));
+        }
+
+        if let Some(fs) = self.fs_cache.get(id) {
+            return Ok(fs);
+        }
+
+        // Objects are immutable, so whatever we parse here stays valid for as long as it is
+        // cached
+        let fs = Arc::new(self.parse_fs(id)?);
+        self.fs_cache.insert(id, fs.clone());
+
+        Ok(fs)
+    }
+
+    /// Find the entry called `name` in the directory with the given id. An entry whose name
+    /// matches exactly is preferred over one which only matches by the library's `NameMatching`.
+    pub fn lookup_dirent(&self, dir_id: Sha1, name: &str) -> Result<Option<DirentJson>, SeafError> {
+        let index = self.dir_index(dir_id)?;
+
+        if let Some(de) = index.by_name.get(name) {
+            return Ok(Some(de.clone()));
+        }
+
+        if self.name_matching.is_exact() {
+            return Ok(None);
         }
+
+        Ok(index.by_key.get(&self.name_matching.key(name)).cloned())
     }
 
-    pub fn fs_iterator(&self) -> FsIterator {
+    /// Find the id of the fs object at `path`, relative to the root of the library. Returns None
+    /// if there is nothing at that path.
+    pub fn resolve_path(&self, path: &Path) -> Result<Option<Sha1>, SeafError> {
+        let mut id = self.head_commit.root_id;
+
+        for c in path.components() {
+            let name = match c {
+                Component::Normal(n) => n,
+                Component::CurDir | Component::RootDir => continue,
+                Component::ParentDir | Component::Prefix(_) => return Ok(None),
+            };
+
+            let de = match name.to_str().map(|name| self.lookup_dirent(id, name)) {
+                Some(Ok(de)) => de,
+                // Nothing can be below a file
+                Some(Err(SeafError::WrongFsType)) | None => None,
+                Some(Err(e)) => return Err(e),
+            };
+
+            match de {
+                Some(de) => id = de.id,
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(id))
+    }
+
+    fn dir_index(&self, dir_id: Sha1) -> Result<Arc<DirIndex>, SeafError> {
+        if let Some(index) = self.dir_indexes.get(dir_id) {
+            return Ok(index);
+        }
+
+        let dir = self.load_fs_shared(dir_id)?.as_ref().clone().try_dir()?;
+        let index = Arc::new(DirIndex::new(dir_id, dir, self.name_matching));
+        self.dir_indexes.insert(dir_id, index.clone());
+
+        Ok(index)
+    }
+
+    pub fn fs_iterator(&self) -> FsIterator<'_> {
         FsIterator::new(self)
     }
 
-    fn obj_path(&self, ty: &str, id: Sha1) -> PathBuf {
-        full_obj_path(&self.location, ty, id)
+    /// Add up the logical size of the tree below the directory `root_id`. Objects are not added
+    /// to the fs cache, so walking a large tree does not push out whatever is being served.
+    pub fn tree_usage(&self, root_id: Sha1) -> Result<UsageStats, SeafError> {
+        let mut usage = UsageStats::default();
+        let mut stack = vec![root_id];
+
+        while let Some(id) = stack.pop() {
+            let fs = match self.fs_cache.get(id) {
+                Some(fs) => fs,
+                None if id == EMPTY_SHA1 => Arc::new(FsJson::Dir(EMPTY_DIR_JSON)),
+                None => Arc::new(self.parse_fs(id)?),
+            };
+
+            match fs.as_ref() {
+                FsJson::Dir(d) => {
+                    usage.dirs += 1;
+                    stack.extend(d.dirents.iter().map(|de| de.id));
+                }
+                FsJson::File(f) => {
+                    usage.files += 1;
+                    usage.bytes += f.size;
+                }
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// Add up the space taken by the blocks of the library. Blocks shared between files are
+    /// only counted once.
+    pub fn block_usage(&self) -> Result<UsageStats, SeafError> {
+        let mut usage = UsageStats::default();
+
+        for e in WalkDir::new(obj_type_path(&self.location, "blocks")) {
+            let e = e?;
+            if e.file_type().is_file() {
+                usage.files += 1;
+                usage.bytes += e.metadata()?.len();
+            }
+        }
+
+        Ok(usage)
+    }
+
+    fn parse_fs(&self, id: Sha1) -> Result<FsJson, SeafError> {
+        parse_fs_json(&full_obj_path(&self.location, "fs", id))
+            .map_err(|e| ObjectRef::new(&self.location, ObjType::Fs, id).wrap(e))
     }
 
     pub fn file_by_id(&self, id: Sha1) -> Result<FileJson, SeafError> {
         self.load_fs(id)?.try_file()
     }
 
+    /// Open a reader for `file`. No blocks are touched until the first read, so this cannot fail
+    /// because of missing blocks. Blocks of encrypted libraries cannot be read, so neither can
+    /// their files.
     pub fn file_reader(&self, file: &FileJson) -> Result<FileReader, SeafError> {
-        let fbr = FileBlockReader::new(file, self.location.clone())?;
-        Ok(FileReader::new(fbr))
+        self.check_not_encrypted()?;
+        Ok(self.make_file_reader(file, Arc::new(BlockLayout::new())))
+    }
+
+    /// Open a reader for the file with the given id. Block offsets learned while reading are
+    /// remembered, so that they need not be looked up again the next time the file is opened.
+    pub fn file_reader_by_id(&self, id: Sha1) -> Result<FileReader, SeafError> {
+        self.check_not_encrypted()?;
+        let file = self.file_by_id(id)?;
+        let layout = match self.block_layouts.get(id) {
+            Some(l) => l,
+            None => {
+                let l = Arc::new(BlockLayout::new());
+                self.block_layouts.insert(id, l.clone());
+                l
+            }
+        };
+
+        Ok(self.make_file_reader(&file, layout))
+    }
+
+    fn check_not_encrypted(&self) -> Result<(), SeafError> {
+        match self.head_commit.encrypted {
+            true => Err(SeafError::Encrypted(self.location.uuid.clone())),
+            false => Ok(()),
+        }
+    }
+
+    fn make_file_reader(&self, file: &FileJson, layout: Arc<BlockLayout>) -> FileReader {
+        let fbr = FileBlockReader {
+            location: self.location.clone(),
+            block_files: self.block_files.clone(),
+            block_cache: self.block_cache.clone(),
+            prefetcher: self.prefetcher.clone(),
+            block_ids: file.block_ids.clone(),
+            layout,
+            access: Mutex::new(AccessPattern::default()),
+            size: file.size,
+        };
+        FileReader::new(fbr)
     }
 }
 
-fn find_head_commit(ll: &LibraryLocation) -> Result<CommitJson, SeafError> {
-    let mut head_commit: Option<CommitJson> = None;
+/// Index of the entries of a directory by name, so that lookups in large directories need not
+/// scan every entry
+#[derive(Debug)]
+struct DirIndex {
+    by_name: HashMap<String, DirentJson>,
+
+    /// Entries by their `NameMatching` key. Empty if names are matched exactly.
+    by_key: HashMap<String, DirentJson>,
+}
 
-    // The head commit is assumed to be the most recent commit
-    for c in commit_iterator(ll) {
-        let c = c?;
+impl DirIndex {
+    fn new(dir_id: Sha1, dir: DirJson, matching: NameMatching) -> DirIndex {
+        let mut by_name: HashMap<String, DirentJson> = HashMap::new();
+        let mut by_key: HashMap<String, DirentJson> = HashMap::new();
 
-        if let Some(ref hc) = head_commit {
-            if c.ctime > hc.ctime {
-                head_commit = Some(c);
+        for de in &dir.dirents {
+            // A broken dir may hold the same name twice, in which case the first one wins
+            if by_name.contains_key(&de.name) {
+                warn!("Name {:?} appears more than once in dir {dir_id}", de.name);
+                continue;
             }
+            by_name.insert(de.name.clone(), de.clone());
+
+            if matching.is_exact() {
+                continue;
+            }
+            let key = matching.key(&de.name);
+
+            // Of the names which collide, the one which sorts first wins, no matter which order
+            // the entries are stored in
+            if let Some(other) = by_key.get(&key) {
+                let (kept, lost) = if other.name < de.name {
+                    (&other.name, &de.name)
+                } else {
+                    (&de.name, &other.name)
+                };
+                warn!("Names {kept:?} and {lost:?} in dir {dir_id} collide, using {kept:?}");
+
+                if other.name < de.name {
+                    continue;
+                }
+            }
+
+            by_key.insert(key, de.clone());
+        }
+
+        DirIndex { by_name, by_key }
+    }
+}
+
+/// Rules for matching names looked up in a library against the names stored in it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NameMatching {
+    pub case_insensitive: bool,
+    pub normalize: Normalization,
+}
+
+/// Unicode normalization form names are compared in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Normalization {
+    /// Compare names as they are
+    #[default]
+    None,
+
+    /// Compare names in composed form, as written by most clients
+    Nfc,
+
+    /// Compare names in decomposed form, as written by macOS
+    Nfd,
+}
+
+impl std::str::FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Normalization, String> {
+        match s {
+            "none" => Ok(Normalization::None),
+            "nfc" => Ok(Normalization::Nfc),
+            "nfd" => Ok(Normalization::Nfd),
+            _ => Err(format!(
+                "Unknown normalization {s:?}, expected nfc, nfd or none"
+            )),
+        }
+    }
+}
+
+impl NameMatching {
+    /// Whether names only match if they are the same byte for byte
+    pub fn is_exact(&self) -> bool {
+        !self.case_insensitive && self.normalize == Normalization::None
+    }
+
+    /// Turn `name` into the key it is matched by. Names match if their keys are equal.
+    pub fn key(&self, name: &str) -> String {
+        // Case is folded first, as lowercasing may leave a name which is no longer normalized
+        let name = if self.case_insensitive {
+            name.to_lowercase()
         } else {
-            head_commit = Some(c);
+            name.to_string()
+        };
+
+        match self.normalize {
+            Normalization::None => name,
+            Normalization::Nfc => name.nfc().collect(),
+            Normalization::Nfd => name.nfd().collect(),
+        }
+    }
+}
+
+/// Amount of data in a tree or a block store
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageStats {
+    pub bytes: u64,
+    pub files: u64,
+    pub dirs: u64,
+}
+
+impl std::ops::Add for UsageStats {
+    type Output = UsageStats;
+
+    fn add(self, other: UsageStats) -> UsageStats {
+        UsageStats {
+            bytes: self.bytes + other.bytes,
+            files: self.files + other.files,
+            dirs: self.dirs + other.dirs,
+        }
+    }
+}
+
+/// Bounded LRU cache of objects derived from storage, such as parsed fs objects or open block
+/// files. Shared between all clones of a `Library`.
+#[derive(Debug)]
+struct ObjCache<T> {
+    /// None if caching is disabled
+    lru: Option<Mutex<LruCache<Sha1, Arc<T>>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Counters describing how well a cache is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+impl<T> ObjCache<T> {
+    fn new(size: usize) -> ObjCache<T> {
+        ObjCache {
+            lru: NonZeroUsize::new(size).map(|s| Mutex::new(LruCache::new(s))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, id: Sha1) -> Option<Arc<T>> {
+        let r = self
+            .lru
+            .as_ref()
+            .and_then(|lru| lru.lock().unwrap().get(&id).cloned());
+
+        match r {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        r
+    }
+
+    /// Check whether an object is cached, without counting it as a use
+    fn contains(&self, id: Sha1) -> bool {
+        match &self.lru {
+            Some(lru) => lru.lock().unwrap().contains(&id),
+            None => false,
         }
     }
 
-    head_commit.ok_or(SeafError::NoHeadCommit)
+    fn insert(&self, id: Sha1, obj: Arc<T>) {
+        if let Some(lru) = &self.lru {
+            lru.lock().unwrap().put(id, obj);
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        let (entries, capacity) = match &self.lru {
+            Some(lru) => {
+                let lru = lru.lock().unwrap();
+                (lru.len(), lru.cap().get())
+            }
+            None => (0, 0),
+        };
+
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries,
+            capacity,
+        }
+    }
+}
+
+fn find_head_commit(ll: &LibraryLocation) -> Result<CommitJson, SeafError> {
+    CommitScan::default().scan(ll)
+}
+
+/// Find the commit whose file was written last. Only that one commit needs to be parsed.
+fn find_recent_commit(ll: &LibraryLocation) -> Result<CommitJson, SeafError> {
+    let path = commits_path(ll)?;
+
+    let mut recent: Option<(SystemTime, PathBuf)> = None;
+
+    for de in WalkDir::new(path) {
+        let de = de?;
+        if !de.file_type().is_file() {
+            continue;
+        }
+
+        let mtime = de.metadata()?.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        if recent.as_ref().is_none_or(|(t, _)| mtime > *t) {
+            recent = Some((mtime, de.into_path()));
+        }
+    }
+
+    match recent {
+        Some((_, path)) => parse_commit_file(&path),
+        None => Err(SeafError::NoHeadCommit),
+    }
+}
+
+/// Path of the commits of a library, which must exist for there to be a library at all
+fn commits_path(ll: &LibraryLocation) -> Result<PathBuf, SeafError> {
+    let path = obj_type_path(ll, "commits");
+    if !path.is_dir() {
+        return Err(SeafError::NotFound(format!(
+            "Library {} in {}",
+            ll.uuid,
+            ll.repo_path.display()
+        )));
+    }
+
+    Ok(path)
 }
 
 fn commit_iterator(ll: &LibraryLocation) -> CommitIterator {
@@ -158,7 +729,14 @@ impl FsIterator<'_> {
         // TODO Too much copying is going on here, optimise
         let nr_state = match &mut self.state {
             FsItState::Root(root_id) => {
-                let d = self.lib.load_fs(*root_id)?.unwrap_dir();
+                let d = match self.lib.load_fs(*root_id).and_then(FsJson::try_dir) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        // Without the root there is nothing to walk, so do not try again
+                        self.clear();
+                        return Err(e);
+                    }
+                };
 
                 self.state = FsItState::NotRoot(FsItNrState {
                     stack: vec![d],
@@ -175,7 +753,10 @@ impl FsIterator<'_> {
 
         while !nr_state.stack.is_empty() {
             if let Some(de) = nr_state.stack.last_mut().unwrap().dirents.pop() {
-                let fs = self.lib.load_fs(de.id)?;
+                let fs = self
+                    .lib
+                    .load_fs(de.id)
+                    .map_err(|e| e.context(nr_state.path.join(&de.name).display().to_string()))?;
                 let path_before = nr_state.path.clone();
 
                 if let FsJson::Dir(ref d) = fs {
@@ -223,7 +804,7 @@ impl Iterator for FsIterator<'_> {
 
 fn find_commit(location: &LibraryLocation, id: Sha1) -> Result<CommitJson, SeafError> {
     let path = full_obj_path(location, "commits", id);
-    parse_commit_file(&path)
+    parse_commit_file(&path).map_err(|e| ObjectRef::new(location, ObjType::Commit, id).wrap(e))
 }
 
 fn parse_commit_file(filename: &Path) -> Result<CommitJson, SeafError> {
@@ -233,6 +814,7 @@ fn parse_commit_file(filename: &Path) -> Result<CommitJson, SeafError> {
     Ok(c)
 }
 
+/// The commits of a library, in order of file name so that the walk is the same on any filesystem
 #[derive(Debug)]
 pub struct CommitIterator {
     it: walkdir::IntoIter,
@@ -241,7 +823,7 @@ pub struct CommitIterator {
 impl CommitIterator {
     pub fn new(path: &Path) -> CommitIterator {
         CommitIterator {
-            it: WalkDir::new(path).into_iter(),
+            it: WalkDir::new(path).sort_by_file_name().into_iter(),
         }
     }
 }
@@ -266,6 +848,41 @@ impl Iterator for CommitIterator {
     }
 }
 
+/// Keeps track of the newest commit of a library as commits are added to it, parsing each commit
+/// file only the first time it is seen
+#[derive(Debug, Default)]
+pub struct CommitScan {
+    seen: HashSet<PathBuf>,
+    newest: Option<CommitJson>,
+}
+
+impl CommitScan {
+    /// Find the newest commit of `lib`, parsing only the commits added since the last call
+    pub fn latest(&mut self, lib: &Library) -> Result<CommitJson, SeafError> {
+        self.scan(&lib.location)
+    }
+
+    fn scan(&mut self, ll: &LibraryLocation) -> Result<CommitJson, SeafError> {
+        let path = commits_path(ll)?;
+
+        for de in WalkDir::new(path) {
+            let de = de?;
+            if !de.file_type().is_file() || self.seen.contains(de.path()) {
+                continue;
+            }
+
+            // The head commit is assumed to be the most recent commit
+            let c = parse_commit_file(de.path())?;
+            self.seen.insert(de.into_path());
+            if self.newest.as_ref().is_none_or(|n| c.ctime > n.ctime) {
+                self.newest = Some(c);
+            }
+        }
+
+        self.newest.clone().ok_or(SeafError::NoHeadCommit)
+    }
+}
+
 #[derive(Debug)]
 pub struct FileReader {
     block_reader: FileBlockReader,
@@ -279,18 +896,24 @@ impl FileReader {
             byte_pos: 0,
         }
     }
+
+    /// Read from an absolute offset without moving the cursor. Unlike `read`, this only needs a
+    /// shared reference, so several threads can read from the same file at once.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.try_read_at(offset, buf).map_err(io::Error::from)
+    }
+
+    /// Like `read_at`, but keeps the details of what went wrong, such as which block failed
+    pub fn try_read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, SeafError> {
+        self.block_reader.read_at_offset(offset, buf)
+    }
 }
 
 impl Read for FileReader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        match self.block_reader.read_at_offset(self.byte_pos, buf) {
-            Ok(s) => {
-                self.byte_pos += s as u64;
-                Ok(s)
-            }
-            Err(SeafError::IO(_, e)) => Err(e),
-            Err(e) => Err(io::Error::from(e)),
-        }
+        let s = self.read_at(self.byte_pos, buf)?;
+        self.byte_pos += s as u64;
+        Ok(s)
     }
 }
 
@@ -327,95 +950,286 @@ impl Seek for FileReader {
 #[derive(Debug)]
 struct FileBlockReader {
     location: Arc<LibraryLocation>,
+    block_files: Arc<ObjCache<fs::File>>,
+    block_cache: Arc<ObjCache<Vec<u8>>>,
+    prefetcher: Option<Arc<Prefetcher>>,
     block_ids: Vec<Sha1>,
-    block_sizes: Vec<usize>,
-    block_starts: Vec<usize>,
-    size: usize,
+    layout: Arc<BlockLayout>,
+    access: Mutex<AccessPattern>,
+    size: u64,
 }
 
-impl FileBlockReader {
-    fn new(file: &FileJson, location: Arc<LibraryLocation>) -> Result<FileBlockReader, SeafError> {
-        let mut block_sizes = vec![];
-        let mut block_starts = vec![];
-        let mut pos = 0;
+/// What a reader has been doing recently, to tell sequential reads from random access
+#[derive(Debug, Default)]
+struct AccessPattern {
+    /// Where the last read ended, if there was one
+    next_offset: Option<u64>,
 
-        for id in &file.block_ids {
-            let path = full_obj_path(&location, "blocks", *id);
-            let md = fs::metadata(&path).map_err(|e| SeafError::IO(path.to_owned(), e))?;
-            let l = md.len() as usize;
+    /// Number of sequential reads in a row
+    streak: u32,
+
+    /// Index of the first block not yet queued for prefetching
+    prefetched_until: usize,
+}
 
-            block_sizes.push(l);
-            block_starts.push(pos);
-            pos += l as usize;
+/// Reads blocks ahead of sequential readers on a pool of background threads
+#[derive(Debug)]
+struct Prefetcher {
+    /// Number of blocks to stay ahead of the reader
+    window: usize,
+    pool: ThreadPool,
+
+    /// Blocks queued or being read, so that they are not queued twice
+    in_flight: Arc<Mutex<HashSet<Sha1>>>,
+}
+
+impl Prefetcher {
+    fn new(window: usize) -> Prefetcher {
+        Prefetcher {
+            window,
+            pool: threadpool::Builder::new()
+                .num_threads(PREFETCH_THREADS)
+                .thread_name("seafuse-prefetch".into())
+                .build(),
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    fn fetch(&self, id: Sha1, reader: &FileBlockReader) {
+        if reader.block_cache.contains(id) || !self.in_flight.lock().unwrap().insert(id) {
+            return;
         }
 
-        Ok(FileBlockReader {
-            location,
-            block_ids: file.block_ids.clone(),
-            block_sizes,
-            block_starts,
-            size: pos,
-        })
+        let location = reader.location.clone();
+        let block_files = reader.block_files.clone();
+        let block_cache = reader.block_cache.clone();
+        let in_flight = self.in_flight.clone();
+
+        self.pool.execute(move || {
+            match load_block(&location, &block_files, id) {
+                Ok(data) => block_cache.insert(id, Arc::new(data)),
+                Err(e) => debug!("Failed to prefetch block {id}: {}", e.report()),
+            }
+
+            in_flight.lock().unwrap().remove(&id);
+        });
     }
+}
 
-    fn read_at_offset(&self, offset: u64, buf: &mut [u8]) -> Result<usize, SeafError> {
-        let to_read = buf.len();
-        let mut have_read = 0;
+/// Offsets of the blocks making up a file. Learning the size of a block means a trip to storage,
+/// so this is done lazily, only as far into the file as reads have reached so far. The layout of
+/// a file never changes and can be shared by every reader of it.
+#[derive(Debug)]
+struct BlockLayout {
+    /// Start offsets of the blocks learned so far. Once complete, there is one more entry than
+    /// there are blocks, the last one being the file size.
+    starts: Mutex<Vec<u64>>,
+}
 
-        match self.find_start_block(offset) {
-            None => Ok(0),
-            Some((mut block_idx, mut block_offset)) => {
-                while have_read < to_read && block_idx < self.block_ids.len() {
-                    let this_block_size = self.block_sizes[block_idx];
-                    let to_read_this_block =
-                        min(to_read - have_read, this_block_size - block_offset);
-                    let file_path =
-                        full_obj_path(&self.location, "blocks", self.block_ids[block_idx]);
+impl BlockLayout {
+    fn new() -> BlockLayout {
+        BlockLayout {
+            starts: Mutex::new(vec![0]),
+        }
+    }
 
-                    || -> Result<(), io::Error> {
-                        let mut file = fs::File::open(&file_path)?;
+    /// Find the blocks covering the byte range `[offset, end)`, learning block sizes with
+    /// `block_size` as needed. Returns the index of the first block along with the start offsets
+    /// of it and the following blocks, up to and including the end of the block containing
+    /// `end - 1`.
+    fn resolve<F>(
+        &self,
+        offset: u64,
+        end: u64,
+        block_count: usize,
+        file_size: u64,
+        block_size: F,
+    ) -> Result<(usize, Vec<u64>), SeafError>
+    where
+        F: Fn(usize) -> Result<u64, SeafError>,
+    {
+        let (known, mut learned) = {
+            let starts = self.starts.lock().unwrap();
+            (starts.len(), vec![*starts.last().unwrap()])
+        };
 
-                        file.seek(SeekFrom::Start(block_offset as u64))?;
+        // Look up block sizes without holding the lock, so that readers of the part already known
+        // are not kept waiting on storage
+        while known + learned.len() - 1 <= block_count && *learned.last().unwrap() < end {
+            let i = known + learned.len() - 2;
+            let next = if i + 1 == block_count {
+                // The last block runs until the end of the file, no need to look it up
+                file_size
+            } else {
+                learned.last().unwrap() + block_size(i)?
+            };
+            learned.push(next);
+        }
 
-                        file.read_exact(&mut buf[have_read..have_read + to_read_this_block])?;
+        let mut starts = self.starts.lock().unwrap();
+        // Another reader may have learned some of these in the meantime, with the same result
+        let have = starts.len() + 1 - known;
+        if have < learned.len() {
+            starts.extend_from_slice(&learned[have..]);
+        }
 
-                        Ok(())
-                    }()
-                    .map_err(|e| SeafError::IO(file_path.to_owned(), e))?;
+        let first = bisection::bisect_right(&starts, &offset) - 1;
+        // Clamp for the benefit of corrupt files whose blocks do not cover their size
+        let last = min(bisection::bisect_left(&starts, &end), starts.len() - 1);
 
-                    have_read += to_read_this_block;
-                    block_idx += 1;
-                    block_offset = 0;
-                }
+        Ok((first, starts[first..=last].to_vec()))
+    }
+}
+
+impl FileBlockReader {
+    fn read_at_offset(&self, offset: u64, buf: &mut [u8]) -> Result<usize, SeafError> {
+        if buf.is_empty() || offset >= self.size {
+            return Ok(0);
+        }
 
-                Ok(have_read)
+        let end = min(offset + buf.len() as u64, self.size);
+        let block_count = self.block_ids.len();
+        let (first_block, starts) =
+            self.layout
+                .resolve(offset, end, block_count, self.size, |i| {
+                    let id = self.block_ids[i];
+                    let md = self
+                        .open_block(id)?
+                        .metadata()
+                        .map_err(|e| block_error(&self.location, id, e))?;
+                    Ok(md.len())
+                })?;
+
+        let mut pos = offset;
+        for (i, w) in starts.windows(2).enumerate() {
+            let (block_start, block_end) = (w[0], w[1]);
+            let block_id = self.block_ids[first_block + i];
+            let to_read_this_block = min(end, block_end).saturating_sub(pos);
+            let buf_pos = (pos - offset) as usize;
+
+            if to_read_this_block == 0 {
+                continue;
             }
+
+            let dest = &mut buf[buf_pos..buf_pos + to_read_this_block as usize];
+            self.read_block(block_id, pos - block_start, dest)?;
+
+            pos += to_read_this_block;
         }
+
+        self.note_access(offset, pos, first_block + starts.len().saturating_sub(2));
+
+        Ok((pos - offset) as usize)
     }
 
-    fn find_start_block(&self, offset: u64) -> Option<(usize, usize)> {
-        let offset = offset as usize;
-        let next_block_idx = bisection::bisect_right(&self.block_starts, &offset);
-        if next_block_idx == 0 {
-            return None;
+    fn read_block(&self, id: Sha1, offset: u64, dest: &mut [u8]) -> Result<(), SeafError> {
+        if self.prefetcher.is_some() {
+            if let Some(data) = self.block_cache.get(id) {
+                let offset = offset as usize;
+                return match data.get(offset..offset + dest.len()) {
+                    Some(src) => {
+                        dest.copy_from_slice(src);
+                        Ok(())
+                    }
+                    None => Err(block_error(
+                        &self.location,
+                        id,
+                        io::ErrorKind::UnexpectedEof.into(),
+                    )),
+                };
+            }
         }
 
-        let block_idx = next_block_idx - 1;
-        let block_start = self.block_starts[block_idx];
-        assert!(offset >= block_start);
+        self.open_block(id)?
+            .read_exact_at(dest, offset)
+            .map_err(|e| block_error(&self.location, id, e))
+    }
 
-        let block_offset = offset - block_start;
+    /// Keep track of whether the file is being read sequentially, and if so, queue the blocks
+    /// following `last_block` for prefetching
+    fn note_access(&self, offset: u64, end: u64, last_block: usize) {
+        let Some(prefetcher) = &self.prefetcher else {
+            return;
+        };
 
-        let block_size = self.block_sizes[block_idx];
-        if block_offset < block_size {
-            Some((block_idx, block_offset))
+        let mut access = self.access.lock().unwrap();
+
+        if access.next_offset == Some(offset) {
+            access.streak += 1;
         } else {
-            None
+            // Random access, stop prefetching until the reader settles down again
+            access.streak = 0;
+            access.prefetched_until = 0;
+        }
+
+        access.next_offset = Some(end);
+
+        if access.streak < SEQUENTIAL_READS {
+            return;
+        }
+
+        let from = (last_block + 1).max(access.prefetched_until);
+        let until = min(last_block + 1 + prefetcher.window, self.block_ids.len());
+
+        for &id in self.block_ids.get(from..until).unwrap_or_default() {
+            prefetcher.fetch(id, self);
         }
+
+        access.prefetched_until = until.max(access.prefetched_until);
+    }
+
+    fn open_block(&self, id: Sha1) -> Result<Arc<fs::File>, SeafError> {
+        open_block(&self.location, &self.block_files, id)
     }
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq)]
+/// Get an open handle to a block file, reusing a cached one if possible. Reads go through
+/// `read_exact_at`, so the same handle can be shared by any number of readers.
+fn open_block(
+    location: &LibraryLocation,
+    block_files: &ObjCache<fs::File>,
+    id: Sha1,
+) -> Result<Arc<fs::File>, SeafError> {
+    if let Some(f) = block_files.get(id) {
+        return Ok(f);
+    }
+
+    let path = full_obj_path(location, "blocks", id);
+    let f = Arc::new(fs::File::open(path).map_err(|e| block_error(location, id, e))?);
+    block_files.insert(id, f.clone());
+
+    Ok(f)
+}
+
+/// Error for a failure to read the block `id`, saying whether it is missing or truncated
+fn block_error(location: &LibraryLocation, id: Sha1, e: io::Error) -> SeafError {
+    let obj = ObjectRef::new(location, ObjType::Block, id);
+    match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof => {
+            let path = obj.path.clone();
+            obj.wrap(SeafError::IO(path, e))
+        }
+        _ => SeafError::BlockUnreadable(obj, e),
+    }
+}
+
+/// Read a whole block into memory
+fn load_block(
+    location: &LibraryLocation,
+    block_files: &ObjCache<fs::File>,
+    id: Sha1,
+) -> Result<Vec<u8>, SeafError> {
+    let f = open_block(location, block_files, id)?;
+
+    || -> Result<Vec<u8>, io::Error> {
+        let mut data = vec![0; f.metadata()?.len() as usize];
+        f.read_exact_at(&mut data, 0)?;
+        Ok(data)
+    }()
+    .map_err(|e| block_error(location, id, e))
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct CommitJson {
     pub commit_id: Sha1,
     pub root_id: Sha1,
@@ -431,6 +1245,21 @@ pub struct CommitJson {
     pub repo_category: Option<String>,
     pub no_local_history: u32,
     pub version: u32,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub encrypted: bool,
+}
+
+/// Seafile writes some boolean fields as the strings "true" and "false"
+fn deserialize_flag<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Bool(b) => Ok(b),
+        serde_json::Value::String(s) => Ok(s == "true"),
+        serde_json::Value::Null => Ok(false),
+        _ => Err(serde::de::Error::custom("invalid flag")),
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
@@ -450,7 +1279,7 @@ pub struct DirJson {
     pub version: u32,
 }
 
-const EMPTY_DIR_JSON: DirJson = DirJson {
+pub(crate) const EMPTY_DIR_JSON: DirJson = DirJson {
     dirents: vec![],
     ty: 0,
     version: 0,
@@ -462,6 +1291,8 @@ pub struct DirentJson {
     pub mode: u32,
     pub mtime: u64,
     pub name: String,
+    #[serde(default)]
+    pub modifier: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -511,7 +1342,7 @@ pub fn parse_fs_json(filename: &Path) -> Result<FsJson, SeafError> {
     Ok(fs)
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default)]
 pub struct Sha1 {
     words: [u32; 5],
 }
@@ -530,6 +1361,11 @@ impl Sha1 {
 
         Some(sha)
     }
+
+    /// The leading 64 bits of the hash
+    pub fn leading_u64(&self) -> u64 {
+        ((self.words[4] as u64) << 32) | self.words[3] as u64
+    }
 }
 
 impl Display for Sha1 {
@@ -549,6 +1385,15 @@ impl Debug for Sha1 {
     }
 }
 
+impl Serialize for Sha1 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 impl<'de> Deserialize<'de> for Sha1 {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -570,11 +1415,208 @@ pub enum SeafError {
     NotImpl,
     NoHeadCommit,
     WrongFsType,
+
+    /// An object the library refers to is not in the storage
+    ObjectMissing(ObjectRef),
+
+    /// An object could not be read back, because it is truncated or cannot be parsed
+    CorruptObject(ObjectRef, Box<dyn Error + Send + Sync>),
+
+    /// A block could not be read for another reason than being missing or truncated
+    BlockUnreadable(ObjectRef, io::Error),
+
+    /// The library is encrypted, which is not supported
+    Encrypted(String),
+
+    /// A library, commit or path which was asked for does not exist
+    NotFound(String),
+
+    /// Another error, along with what was being worked on when it happened
+    Context(String, Box<SeafError>),
+}
+
+/// Type of an object in the storage of a library
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjType {
+    Commit,
+    Fs,
+    Block,
+}
+
+impl ObjType {
+    /// Name of the directory holding objects of this type
+    fn dir_name(self) -> &'static str {
+        match self {
+            ObjType::Commit => "commits",
+            ObjType::Fs => "fs",
+            ObjType::Block => "blocks",
+        }
+    }
+}
+
+impl Display for ObjType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ObjType::Commit => "commit",
+            ObjType::Fs => "fs object",
+            ObjType::Block => "block",
+        })
+    }
+}
+
+/// An object in the storage of a library, for reporting errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectRef {
+    pub uuid: String,
+    pub ty: ObjType,
+    pub id: Sha1,
+    pub path: PathBuf,
+}
+
+impl ObjectRef {
+    fn new(location: &LibraryLocation, ty: ObjType, id: Sha1) -> ObjectRef {
+        ObjectRef {
+            uuid: location.uuid.clone(),
+            ty,
+            id,
+            path: full_obj_path(location, ty.dir_name(), id),
+        }
+    }
+
+    /// Turn an error from reading the object into one saying which object it was
+    fn wrap(self, e: SeafError) -> SeafError {
+        match e {
+            SeafError::IO(_, e) if e.kind() == io::ErrorKind::NotFound => {
+                SeafError::ObjectMissing(self)
+            }
+            SeafError::IO(_, e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                SeafError::CorruptObject(self, Box::new(e))
+            }
+            SeafError::ParseJson(_, e) => SeafError::CorruptObject(self, Box::new(e)),
+            e => e,
+        }
+    }
+}
+
+impl Display for ObjectRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} of library {} ({})",
+            self.ty,
+            self.id,
+            self.uuid,
+            self.path.display()
+        )
+    }
+}
+
+impl Display for SeafError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SeafError::IO(path, _) => write!(f, "Failed to access {}", path.display()),
+            SeafError::ParseJson(path, _) => write!(f, "Failed to parse {}", path.display()),
+            SeafError::WalkDir(_) => f.write_str("Failed to walk directory"),
+            SeafError::NotImpl => f.write_str("Not implemented"),
+            SeafError::NoHeadCommit => f.write_str("Library has no commits"),
+            SeafError::WrongFsType => f.write_str("Fs object is of the wrong type"),
+            SeafError::ObjectMissing(obj) => write!(f, "Missing {obj}"),
+            SeafError::CorruptObject(obj, _) => write!(f, "Corrupt {obj}"),
+            SeafError::BlockUnreadable(obj, _) => write!(f, "Failed to read {obj}"),
+            SeafError::Encrypted(uuid) => write!(f, "Library {uuid} is encrypted"),
+            SeafError::NotFound(what) => write!(f, "{what} not found"),
+            SeafError::Context(what, _) => f.write_str(what),
+        }
+    }
+}
+
+impl Error for SeafError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SeafError::IO(_, e) => Some(e),
+            SeafError::ParseJson(_, e) => Some(e),
+            SeafError::WalkDir(e) => Some(e),
+            SeafError::CorruptObject(_, e) => Some(e.as_ref()),
+            SeafError::BlockUnreadable(_, e) => Some(e),
+            SeafError::Context(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl SeafError {
+    /// The errno to report this error to applications with. Objects of the wrong type are
+    /// reported as ENOTDIR, as most operations expect a directory; opening a directory as a file
+    /// is EISDIR, which callers which expect a file must map themselves. Failing to read a block
+    /// is EIO, as that is the only error read(2) gives for a file which is still there.
+    pub fn errno(&self) -> c_int {
+        match self {
+            SeafError::IO(_, e) => io_errno(e),
+            // Errors without an underlying I/O error are symlink loops
+            SeafError::WalkDir(e) => e.io_error().map(io_errno).unwrap_or(ELOOP),
+            SeafError::ParseJson(..) => EIO,
+            SeafError::NotImpl => ENOSYS,
+            SeafError::NoHeadCommit => ENOENT,
+            SeafError::WrongFsType => ENOTDIR,
+            SeafError::ObjectMissing(obj) => match obj.ty {
+                ObjType::Block => EIO,
+                ObjType::Commit | ObjType::Fs => ENOENT,
+            },
+            SeafError::CorruptObject(..) => EIO,
+            SeafError::BlockUnreadable(..) => EIO,
+            SeafError::Encrypted(_) => EACCES,
+            SeafError::NotFound(_) => ENOENT,
+            SeafError::Context(_, e) => e.errno(),
+        }
+    }
+
+    fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            SeafError::IO(_, e) | SeafError::BlockUnreadable(_, e) => e.kind(),
+            SeafError::ObjectMissing(_) | SeafError::NotFound(_) => io::ErrorKind::NotFound,
+            SeafError::CorruptObject(..) | SeafError::ParseJson(..) => io::ErrorKind::InvalidData,
+            SeafError::Encrypted(_) => io::ErrorKind::PermissionDenied,
+            SeafError::Context(_, e) => e.io_kind(),
+            _ => io::ErrorKind::Other,
+        }
+    }
+
+    /// Say what was being worked on when the error happened
+    pub fn context(self, what: impl Into<String>) -> SeafError {
+        SeafError::Context(what.into(), Box::new(self))
+    }
+
+    /// The message of the error followed by those of its causes, for showing to users
+    pub fn report(&self) -> String {
+        let mut msg = self.to_string();
+        let mut source = self.source();
+        while let Some(e) = source {
+            msg += &format!(": {e}");
+            source = e.source();
+        }
+        msg
+    }
+}
+
+/// The errno to report an I/O error to applications with. Errors from looking up a path in the
+/// storage keep their error number, anything else, such as running out of file descriptors, is
+/// none of the application's business and becomes EIO.
+pub fn io_errno(e: &io::Error) -> c_int {
+    match e.raw_os_error() {
+        Some(n @ (ENOENT | ENOTDIR | EACCES | ELOOP | ENAMETOOLONG)) => n,
+        Some(_) => EIO,
+        None => match e.kind() {
+            io::ErrorKind::NotFound => ENOENT,
+            io::ErrorKind::PermissionDenied => EACCES,
+            io::ErrorKind::NotADirectory => ENOTDIR,
+            _ => EIO,
+        },
+    }
 }
 
 impl From<SeafError> for io::Error {
     fn from(e: SeafError) -> Self {
-        Self::other(format!("{e:?}"))
+        Self::new(e.io_kind(), e)
     }
 }
 
@@ -0,0 +1,125 @@
+// Copyright 2025 Johan Förberg
+// SPDX-License-Identifier: MIT
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::process::Output;
+use tempdir::TempDir;
+
+pub mod util;
+use util::*;
+
+/// Exit code for missing input, from sysexits(3)
+const EX_NOINPUT: i32 = 66;
+
+/// Name, mtime, permissions and contents of each entry in an archive
+type Listing = Vec<(String, u64, u32, Vec<u8>)>;
+
+/// Run `seafuse export` on the basic repo, writing to `output`
+fn export(format: &str, output: &Path, prefix: Option<&str>) -> Output {
+    seafuse()
+        .args(["export", "--format", format, "-o"])
+        .arg(output)
+        .args([TR_BASIC.path, TR_BASIC.uuid])
+        .args(prefix)
+        .output()
+        .unwrap()
+}
+
+fn export_listing(format: &str, prefix: Option<&str>) -> Listing {
+    let tmp = TempDir::new("seafuse-export").unwrap();
+    let output = tmp.path().join("archive");
+    let out = export(format, &output, prefix);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    let f = fs::File::open(&output).unwrap();
+    let tar_listing = |r: &mut dyn Read| -> Listing {
+        tar::Archive::new(r)
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let mut e = e.unwrap();
+                let mut data = vec![];
+                e.read_to_end(&mut data).unwrap();
+                let h = e.header();
+                (
+                    e.path().unwrap().display().to_string(),
+                    h.mtime().unwrap(),
+                    h.mode().unwrap(),
+                    data,
+                )
+            })
+            .collect()
+    };
+
+    let mut listing = match format {
+        "tar" => tar_listing(&mut { f }),
+        "tar.gz" => tar_listing(&mut flate2::read::GzDecoder::new(f)),
+        "zip" => {
+            let mut z = zip::ZipArchive::new(f).unwrap();
+            (0..z.len())
+                .map(|i| {
+                    let mut e = z.by_index(i).unwrap();
+                    let mut data = vec![];
+                    e.read_to_end(&mut data).unwrap();
+                    let mtime = e
+                        .extra_data_fields()
+                        .find_map(|f| match f {
+                            zip::extra_fields::ExtraField::ExtendedTimestamp(t) => t.mod_time(),
+                            _ => None,
+                        })
+                        .unwrap();
+                    let dos_time = e.last_modified().unwrap();
+                    assert_eq!(dos_time.second() as u32, mtime % 60 / 2 * 2);
+                    let name = e.name().trim_end_matches('/').to_owned();
+                    (name, mtime as u64, e.unix_mode().unwrap() & 0o7777, data)
+                })
+                .collect()
+        }
+        _ => unreachable!(),
+    };
+    listing.sort();
+    listing
+}
+
+#[test]
+fn export_formats() {
+    let expected = [
+        ("somedir", 1739030525, 0o755, &b""[..]),
+        (
+            "somedir/test2.md",
+            1739030525,
+            0o644,
+            b"# test2\n\n\xe2\x80\x8b\n\ntest2\n",
+        ),
+        ("test.md", 1738424124, 0o644, b"# test\n\ntest\n"),
+    ];
+
+    for format in ["tar", "tar.gz", "zip"] {
+        let listing = export_listing(format, None);
+        let listing: Vec<_> = listing
+            .iter()
+            .map(|(name, mtime, mode, data)| (name.as_str(), *mtime, *mode, &data[..]))
+            .collect();
+        assert_eq!(listing, expected, "{format}");
+    }
+
+    let listing = export_listing("zip", Some("somedir/test2.md"));
+    assert_eq!(listing.len(), 1);
+    assert_eq!(listing[0].0, "somedir/test2.md");
+}
+
+#[test]
+fn export_missing_path() {
+    let tmp = TempDir::new("seafuse-export").unwrap();
+    let output = tmp.path().join("archive.tar");
+
+    let out = export("tar", &output, Some("nope"));
+    assert_eq!(out.status.code(), Some(EX_NOINPUT));
+    assert!(!output.exists());
+}
@@ -0,0 +1,423 @@
+// Copyright 2025 Johan Förberg
+// SPDX-License-Identifier: MIT
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::time::UNIX_EPOCH;
+use tempdir::TempDir;
+
+pub mod util;
+use util::*;
+
+/// Exit code for missing input, from sysexits(3)
+const EX_NOINPUT: i32 = 66;
+
+/// Run `seafuse extract` on the library `uuid` of the storage root `source`, writing to `target`
+fn extract(source: &Path, uuid: &str, target: &Path, args: &[&str]) -> Output {
+    seafuse()
+        .arg("extract")
+        .arg(source)
+        .arg(uuid)
+        .arg(target)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Like `extract`, for extractions which are expected to succeed
+fn extract_ok(source: &Path, uuid: &str, target: &Path, args: &[&str]) {
+    let out = extract(source, uuid, target, args);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+}
+
+fn extract_basic(target: &Path, args: &[&str]) {
+    extract_ok(Path::new(TR_BASIC.path), TR_BASIC.uuid, target, args);
+}
+
+/// Everything about an extracted tree which extraction is meant to reproduce
+fn tree_listing(root: &Path) -> Vec<(PathBuf, Option<Vec<u8>>, i64, u32)> {
+    walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .map(|e| {
+            let e = e.unwrap();
+            let md = e.metadata().unwrap();
+            (
+                e.path().strip_prefix(root).unwrap().to_path_buf(),
+                md.is_file().then(|| fs::read(e.path()).unwrap()),
+                md.mtime(),
+                md.mode(),
+            )
+        })
+        .collect()
+}
+
+/// Paths in an extracted tree
+fn tree_paths(root: &Path) -> Vec<String> {
+    tree_listing(root)
+        .into_iter()
+        .map(|(path, ..)| path.display().to_string())
+        .collect()
+}
+
+/// A copy of the basic repo without the fs object of test.md
+fn basic_without_test_md() -> TempDir {
+    let repo = TR_BASIC.copy();
+    fs::remove_file(TR_BASIC.path_in(
+        repo.path(),
+        "fs",
+        "e40b894880747010bf6ec384b83e578f352beed7",
+    ))
+    .unwrap();
+    repo
+}
+
+#[test]
+fn extract_preserves_attrs() {
+    let lib = TR_BASIC.open();
+    let root_id = lib.head_commit.root_id;
+    let file = lib.lookup_dirent(root_id, "test.md").unwrap().unwrap();
+    let dir = lib.lookup_dirent(root_id, "somedir").unwrap().unwrap();
+
+    for no_preserve in [false, true] {
+        let tmp = TempDir::new("seafuse-extract").unwrap();
+        let args: &[&str] = if no_preserve { &["--no-preserve"] } else { &[] };
+        extract_basic(tmp.path(), args);
+
+        let file_md = fs::metadata(tmp.path().join("test.md")).unwrap();
+        let dir_md = fs::metadata(tmp.path().join("somedir")).unwrap();
+        assert_eq!(file_md.mtime() as u64 == file.mtime, !no_preserve);
+        assert_eq!(dir_md.mtime() as u64 == dir.mtime, !no_preserve);
+        if !no_preserve {
+            assert_eq!(file_md.permissions().mode() & 0o777, file.mode & 0o777);
+        }
+    }
+}
+
+#[test]
+fn extract_keep_going() {
+    let repo = basic_without_test_md();
+
+    for jobs in ["1", "4"] {
+        let target = TempDir::new("seafuse-extract").unwrap();
+        let report = target.path().join("report.txt");
+
+        let out = extract(repo.path(), TR_BASIC.uuid, target.path(), &["-j", jobs]);
+        assert!(!out.status.success());
+
+        let out = extract(
+            repo.path(),
+            TR_BASIC.uuid,
+            target.path(),
+            &[
+                "-j",
+                jobs,
+                "--keep-going",
+                "--report",
+                report.to_str().unwrap(),
+            ],
+        );
+        assert_eq!(out.status.code(), Some(EX_NOINPUT));
+        assert!(target.path().join("somedir").is_dir());
+        assert!(fs::read_to_string(report)
+            .unwrap()
+            .starts_with("test.md: Missing fs object"));
+    }
+}
+
+#[test]
+fn extract_partial_file() {
+    let repo = TR_MULTIBLOCK.copy();
+    fs::remove_file(TR_MULTIBLOCK.path_in(
+        repo.path(),
+        "blocks",
+        "fc1776a9f030e90685ee2c5ceba8c0f934f98e02",
+    ))
+    .unwrap();
+    let target = TempDir::new("seafuse-extract").unwrap();
+    let report = repo.path().join("report.json");
+
+    let out = extract(
+        repo.path(),
+        TR_MULTIBLOCK.uuid,
+        target.path(),
+        &[
+            "--keep-going",
+            "--report",
+            report.to_str().unwrap(),
+            "--report-format",
+            "json",
+        ],
+    );
+    assert!(!out.status.success());
+    assert!(!target.path().join("multiblock.txt").exists());
+    assert!(target.path().join("multiblock.txt.partial").exists());
+
+    let report: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(report).unwrap()).unwrap();
+    assert_eq!(report["failures"][0]["path"], "multiblock.txt");
+    assert_eq!(report["failures"][0]["errno"], libc::EIO);
+}
+
+#[test]
+fn parallel_extract_matches_serial() {
+    for repo in [&TR_BASIC, &TR_MULTIBLOCK, &TR_NESTED] {
+        let serial = TempDir::new("seafuse-extract").unwrap();
+        let parallel = TempDir::new("seafuse-extract").unwrap();
+
+        extract_ok(Path::new(repo.path), repo.uuid, serial.path(), &[]);
+        extract_ok(
+            Path::new(repo.path),
+            repo.uuid,
+            parallel.path(),
+            &["-j", "4"],
+        );
+
+        let listing = tree_listing(serial.path());
+        assert!(!listing.is_empty(), "{}", repo.path);
+        assert_eq!(listing, tree_listing(parallel.path()), "{}", repo.path);
+    }
+}
+
+#[test]
+fn extract_prefix_next_to_files() {
+    let repo = TR_BASIC.copy();
+    let lib = TR_BASIC.open();
+    let dir = lib.resolve_path(Path::new("somedir")).unwrap().unwrap();
+    let file_id = "e40b894880747010bf6ec384b83e578f352beed7";
+    TR_BASIC.write_dirents(
+        repo.path(),
+        &dir.to_string(),
+        &[("a.md", file_id), ("b.md", file_id)],
+    );
+
+    // Whichever is walked first, the file outside the prefix must not end the walk of somedir
+    for name in ["a.md", "b.md"] {
+        let target = TempDir::new("seafuse-extract").unwrap();
+        let prefix = format!("somedir/{name}");
+        extract_ok(
+            repo.path(),
+            TR_BASIC.uuid,
+            target.path(),
+            &["--prefix", &prefix],
+        );
+        assert_eq!(tree_paths(target.path()), ["somedir", &prefix]);
+    }
+}
+
+#[test]
+fn extract_update() {
+    let target = TempDir::new("seafuse-extract").unwrap();
+    let test_md = target.path().join("test.md");
+    let test2_md = target.path().join("somedir/test2.md");
+    extract_basic(target.path(), &[]);
+
+    // Same size and time as in the library, so it is taken to be unchanged
+    let mtime = fs::metadata(&test_md).unwrap().modified().unwrap();
+    fs::write(&test_md, "not the file!").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&test_md)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    fs::write(&test2_md, "changed").unwrap();
+
+    extract_basic(target.path(), &["--update"]);
+    assert_eq!(fs::read_to_string(&test_md).unwrap(), "not the file!");
+    assert_eq!(fs::read(&test2_md).unwrap().len(), 20);
+
+    // With a state file, the content id is what counts
+    let state = target.path().join("state.json");
+    let args = ["--update", "--state", state.to_str().unwrap()];
+    extract_basic(target.path(), &args);
+    assert_ne!(fs::read_to_string(&test_md).unwrap(), "not the file!");
+    let ids: BTreeMap<String, String> =
+        serde_json::from_str(&fs::read_to_string(&state).unwrap()).unwrap();
+    let lib = TR_BASIC.open();
+    let file = lib
+        .lookup_dirent(lib.head_commit.root_id, "test.md")
+        .unwrap()
+        .unwrap();
+    assert_eq!(ids.get("test.md"), Some(&file.id.to_string()));
+    assert_eq!(ids.len(), 2);
+
+    fs::File::options()
+        .write(true)
+        .open(&test_md)
+        .unwrap()
+        .set_modified(UNIX_EPOCH)
+        .unwrap();
+    fs::write(&test2_md, "0123456789abcdefghij").unwrap();
+    extract_basic(target.path(), &args);
+    assert_eq!(fs::metadata(&test_md).unwrap().mtime() as u64, file.mtime);
+    assert_eq!(
+        fs::read_to_string(&test2_md).unwrap(),
+        "0123456789abcdefghij"
+    );
+}
+
+#[test]
+fn extract_delete() {
+    let target = TempDir::new("seafuse-extract").unwrap();
+    let state = target.path().join("state.json");
+    fs::create_dir_all(target.path().join("old/dir")).unwrap();
+    fs::write(target.path().join("old/dir/file"), "x").unwrap();
+    fs::write(target.path().join("stray"), "x").unwrap();
+    fs::create_dir(target.path().join("somedir")).unwrap();
+    fs::write(target.path().join("somedir/stray"), "x").unwrap();
+    fs::create_dir(target.path().join("test.md")).unwrap();
+
+    // Only below the prefix
+    extract_basic(target.path(), &["--prefix", "somedir", "--delete"]);
+    assert!(!target.path().join("somedir/stray").exists());
+    assert!(target.path().join("somedir/test2.md").is_file());
+    assert!(target.path().join("old/dir/file").exists());
+
+    extract_basic(
+        target.path(),
+        &["--delete", "--state", state.to_str().unwrap()],
+    );
+    assert!(!target.path().join("old").exists());
+    assert!(!target.path().join("stray").exists());
+    assert!(target.path().join("test.md").is_file());
+    assert!(state.is_file());
+
+    assert_eq!(
+        tree_paths(target.path()),
+        ["somedir", "somedir/test2.md", "state.json", "test.md"]
+    );
+}
+
+#[test]
+fn extract_delete_after_failure() {
+    let repo = basic_without_test_md();
+    let target = TempDir::new("seafuse-extract").unwrap();
+    fs::write(target.path().join("stray"), "x").unwrap();
+
+    let out = extract(
+        repo.path(),
+        TR_BASIC.uuid,
+        target.path(),
+        &["--keep-going", "--delete"],
+    );
+    assert!(!out.status.success());
+    assert!(target.path().join("stray").exists());
+}
+
+#[test]
+fn extract_resume() {
+    let target = TempDir::new("seafuse-extract").unwrap();
+    let journal = target.path().join(".seafuse-journal");
+    let commit_id = TR_BASIC.open().head_commit.commit_id;
+
+    // As if interrupted after writing test.md, while somedir/test2.md was being written
+    fs::write(target.path().join("test.md"), "not the file!").unwrap();
+    fs::create_dir(target.path().join("somedir")).unwrap();
+    fs::write(target.path().join("somedir/test2.md"), "trunc").unwrap();
+    fs::write(
+        &journal,
+        format!("commit {commit_id}\n\"test.md\"\n\"somedir/test2.md"),
+    )
+    .unwrap();
+
+    extract_basic(target.path(), &["--resume"]);
+    assert_eq!(
+        fs::read_to_string(target.path().join("test.md")).unwrap(),
+        "not the file!"
+    );
+    assert_eq!(
+        fs::read(target.path().join("somedir/test2.md"))
+            .unwrap()
+            .len(),
+        20
+    );
+    assert!(!journal.exists());
+
+    // Without a journal, everything is extracted
+    extract_basic(target.path(), &["--resume"]);
+    assert_ne!(
+        fs::read_to_string(target.path().join("test.md")).unwrap(),
+        "not the file!"
+    );
+
+    fs::write(&journal, format!("commit {}\n", "0".repeat(40))).unwrap();
+    let out = extract(
+        Path::new(TR_BASIC.path),
+        TR_BASIC.uuid,
+        target.path(),
+        &["--resume"],
+    );
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(!out.status.success());
+    assert!(stderr.contains("Journal is for commit"), "{stderr}");
+}
+
+#[test]
+fn extract_journal_after_failure() {
+    let repo = basic_without_test_md();
+    let target = TempDir::new("seafuse-extract").unwrap();
+    let commit_id = TR_BASIC.open().head_commit.commit_id;
+
+    let out = extract(repo.path(), TR_BASIC.uuid, target.path(), &["--keep-going"]);
+    assert!(!out.status.success());
+
+    let journal = fs::read_to_string(target.path().join(".seafuse-journal")).unwrap();
+    assert_eq!(
+        journal,
+        format!("commit {commit_id}\n\"somedir/test2.md\"\n")
+    );
+}
+
+#[test]
+fn extract_filters() {
+    let listing = |args: &[&str]| {
+        let target = TempDir::new("seafuse-extract").unwrap();
+        extract_basic(target.path(), args);
+        tree_paths(target.path())
+    };
+
+    assert_eq!(listing(&["--exclude", "somedir"]), ["test.md"]);
+    assert_eq!(
+        listing(&["--include", "*.md", "--max-size", "15"]),
+        ["test.md"]
+    );
+    assert_eq!(
+        listing(&["--newer-than", "2025-02-05"]),
+        ["somedir", "somedir/test2.md"]
+    );
+
+    let patterns = TempDir::new("seafuse-filter").unwrap();
+    let exclude_from = patterns.path().join("exclude");
+    fs::write(&exclude_from, "# Comment\n*.md\n!test2.md\n").unwrap();
+    assert_eq!(
+        listing(&["--exclude-from", exclude_from.to_str().unwrap()]),
+        ["somedir", "somedir/test2.md"]
+    );
+}
+
+#[test]
+fn delete_keeps_excluded() {
+    let target = TempDir::new("seafuse-extract").unwrap();
+    fs::create_dir(target.path().join("somedir")).unwrap();
+    fs::write(target.path().join("somedir/local"), "x").unwrap();
+    fs::write(target.path().join("test.md"), "x").unwrap();
+
+    extract_basic(
+        target.path(),
+        &["--delete", "--exclude", "somedir/", "--exclude", "*.md"],
+    );
+    assert!(target.path().join("somedir/local").exists());
+    assert_eq!(
+        fs::read_to_string(target.path().join("test.md")).unwrap(),
+        "x"
+    );
+}
@@ -2,8 +2,15 @@
 // SPDX-License-Identifier: MIT
 
 use fuser::FUSE_ROOT_ID;
-use libc::{EBADF, ENOENT};
+use libc::{
+    EACCES, EBADF, EINVAL, EIO, EISDIR, ENODATA, ENOENT, ENOTDIR, EROFS, ESTALE, O_RDONLY, O_RDWR,
+    O_TRUNC, O_WRONLY,
+};
 use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
 
 use seafuse::*;
 
@@ -12,7 +19,7 @@ use util::*;
 
 #[test]
 fn readdir_several_files() {
-    let mut fs = SeafFuse::new(TR_BASIC.open());
+    let fs = SeafFuse::new(TR_BASIC.open());
     let mut entries: Vec<OsString> = fs
         .do_readdir(FUSE_ROOT_ID)
         .unwrap()
@@ -27,7 +34,7 @@ fn readdir_several_files() {
 
 #[test]
 fn lookup_file() {
-    let mut fs = SeafFuse::new(TR_BASIC.open());
+    let fs = SeafFuse::new(TR_BASIC.open());
     let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
 
     assert_eq!(attr.size, 13);
@@ -35,7 +42,7 @@ fn lookup_file() {
 
 #[test]
 fn lookup_vs_getattr() {
-    let mut fs = SeafFuse::new(TR_BASIC.open());
+    let fs = SeafFuse::new(TR_BASIC.open());
     let attr1 = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
     let attr2 = fs.do_getattr(attr1.ino).unwrap();
 
@@ -44,17 +51,35 @@ fn lookup_vs_getattr() {
 
 #[test]
 fn lookup_non_existent() {
-    let mut fs = SeafFuse::new(TR_BASIC.open());
+    let fs = SeafFuse::new(TR_BASIC.open());
     let r = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("doesnt_exist"));
 
     assert_eq!(r.unwrap_err(), ENOENT);
 }
 
+#[test]
+fn lookup_case_insensitive() {
+    let lib = TR_BASIC.open().with_name_matching(NameMatching {
+        case_insensitive: true,
+        normalize: Normalization::Nfc,
+    });
+    let fs = SeafFuse::new(lib);
+    let attr1 = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+    let attr2 = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("Test.MD")).unwrap();
+
+    assert_eq!(attr1, attr2);
+    assert_eq!(
+        fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.txt"))
+            .unwrap_err(),
+        ENOENT
+    );
+}
+
 #[test]
 fn read_file() {
-    let mut fs = SeafFuse::new(TR_BASIC.open());
+    let fs = SeafFuse::new(TR_BASIC.open());
     let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
-    let fh = fs.do_open(attr.ino).unwrap();
+    let fh = fs.do_open(attr.ino, O_RDONLY).unwrap();
     let data = fs.do_read(fh, 8, 4).unwrap();
 
     fs.do_release(fh).unwrap();
@@ -62,10 +87,626 @@ fn read_file() {
     assert_eq!(data, "test".as_bytes());
 }
 
+#[test]
+fn wrong_types_map_to_errno() {
+    let fs = SeafFuse::new(TR_BASIC.open());
+    let file = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+    let dir = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("somedir")).unwrap();
+
+    assert_eq!(fs.do_open(dir.ino, O_RDONLY).unwrap_err(), EISDIR);
+    assert_eq!(fs.do_readdir(file.ino).unwrap_err(), ENOTDIR);
+    assert_eq!(
+        fs.do_lookup(file.ino, OsStr::new("x")).unwrap_err(),
+        ENOTDIR
+    );
+    assert_eq!(fs.do_getattr(12345).unwrap_err(), ESTALE);
+}
+
+#[test]
+fn storage_errors_map_to_errno() {
+    let tmp = TR_BASIC.copy();
+    let open = || SeafFuse::new(Library::open(tmp.path(), TR_BASIC.uuid).unwrap());
+    let fs_path = tmp
+        .path()
+        .join("fs")
+        .join(TR_BASIC.uuid)
+        .join("e4/0b894880747010bf6ec384b83e578f352beed7");
+
+    let fs = open();
+    let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+    let fh = fs.do_open(attr.ino, O_RDONLY).unwrap();
+    fs::remove_dir_all(tmp.path().join("blocks")).unwrap();
+    // read(2) never gives ENOENT, which would say the open file was deleted
+    assert_eq!(fs.do_read(fh, 0, 4).unwrap_err(), EIO);
+
+    fs::write(&fs_path, "garbage").unwrap();
+    let r = open().do_lookup(FUSE_ROOT_ID, OsStr::new("test.md"));
+    assert_eq!(r.unwrap_err(), EIO);
+
+    fs::remove_file(&fs_path).unwrap();
+    let r = open().do_lookup(FUSE_ROOT_ID, OsStr::new("test.md"));
+    assert_eq!(r.unwrap_err(), ENOENT);
+}
+
 #[test]
 fn bad_file_handle() {
-    let mut fs = SeafFuse::new(TR_BASIC.open());
+    let fs = SeafFuse::new(TR_BASIC.open());
     let r = fs.do_read(123, 0, 1024);
 
     assert_eq!(r.unwrap_err(), EBADF);
 }
+
+#[test]
+fn seaffuse_is_thread_safe() {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<SeafFuse>();
+    assert_send_sync::<Library>();
+    assert_send_sync::<FileReader>();
+}
+
+#[test]
+fn concurrent_reads() {
+    let fs = Arc::new(SeafFuse::new(TR_BASIC.open()));
+    let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+    let fh = fs.do_open(attr.ino, O_RDONLY).unwrap();
+
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let fs = fs.clone();
+            thread::spawn(move || {
+                let other_fh = fs.do_open(attr.ino, O_RDONLY).unwrap();
+                let a = fs.do_read(fh, 8, 4).unwrap();
+                let b = fs.do_read(other_fh, 0, 6).unwrap();
+                fs.do_release(other_fh).unwrap();
+                (a, b)
+            })
+        })
+        .collect();
+
+    for t in threads {
+        let (a, b) = t.join().unwrap();
+        assert_eq!(a, b"test");
+        assert_eq!(b, b"# test");
+    }
+
+    fs.do_release(fh).unwrap();
+}
+
+#[test]
+fn opendir_snapshot() {
+    let fs = SeafFuse::new(TR_BASIC.open());
+    let fh = fs.do_opendir(FUSE_ROOT_ID).unwrap();
+    let mut entries: Vec<OsString> = fs
+        .do_dir_entries(fh)
+        .unwrap()
+        .iter()
+        .map(|e| e.name.clone())
+        .collect();
+
+    entries.sort();
+    assert_eq!(entries, ["somedir", "test.md"]);
+
+    fs.do_releasedir(fh).unwrap();
+    assert_eq!(fs.do_dir_entries(fh).unwrap_err(), EBADF);
+}
+
+#[test]
+fn readdir_attrs_match_lookup() {
+    let fs = SeafFuse::new(TR_BASIC.open());
+
+    let listing = fs.do_readdir(FUSE_ROOT_ID).unwrap();
+    let inos: Vec<u64> = listing.iter().map(|d| d.ino).collect();
+    assert_ne!(inos[0], inos[1]);
+    assert!(inos.iter().all(|&ino| ino & UNKNOWN_INO_BIT != 0));
+
+    for d in fs.do_readdir(FUSE_ROOT_ID).unwrap() {
+        assert!(inos.contains(&d.ino));
+        fs.do_lookup(FUSE_ROOT_ID, &d.name).unwrap();
+    }
+
+    for d in fs.do_readdir(FUSE_ROOT_ID).unwrap() {
+        let attr = fs.do_lookup(FUSE_ROOT_ID, &d.name).unwrap();
+        assert_eq!(d.attr, attr);
+        assert_eq!(d.kind, attr.kind);
+    }
+}
+
+#[test]
+fn bad_dir_handle() {
+    let fs = SeafFuse::new(TR_BASIC.open());
+
+    assert_eq!(fs.do_releasedir(123).unwrap_err(), EBADF);
+}
+
+#[test]
+fn forget_evicts_inode() {
+    let fs = SeafFuse::new(TR_BASIC.open());
+    let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+    fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+    assert_eq!(fs.inode_count(), 2);
+
+    fs.do_forget(attr.ino, 1);
+    assert!(fs.do_getattr(attr.ino).is_ok());
+
+    fs.do_forget(attr.ino, 1);
+    assert!(fs.do_getattr(attr.ino).is_err());
+    assert_eq!(fs.inode_count(), 1);
+}
+
+#[test]
+fn forget_keeps_open_files() {
+    let fs = SeafFuse::new(TR_BASIC.open());
+    let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+    let fh = fs.do_open(attr.ino, O_RDONLY).unwrap();
+
+    fs.do_forget(attr.ino, 1);
+    assert_eq!(fs.do_getattr(attr.ino).unwrap(), attr);
+    assert_eq!(fs.do_read(fh, 8, 4).unwrap(), b"test");
+
+    fs.do_release(fh).unwrap();
+    assert!(fs.do_getattr(attr.ino).is_err());
+}
+
+#[test]
+fn forget_never_evicts_root() {
+    let fs = SeafFuse::new(TR_BASIC.open());
+
+    fs.do_forget(FUSE_ROOT_ID, 1);
+    assert!(fs.do_getattr(FUSE_ROOT_ID).is_ok());
+}
+
+#[test]
+fn readdir_does_not_allocate_inodes() {
+    let fs = SeafFuse::new(TR_NESTED.open());
+    let fh = fs.do_opendir(FUSE_ROOT_ID).unwrap();
+    fs.do_releasedir(fh).unwrap();
+
+    assert_eq!(fs.inode_count(), 1);
+}
+
+#[test]
+fn switch_commit_updates_root() {
+    let lib = Library::open_for_commit(
+        Path::new(TR_BASIC.path),
+        TR_BASIC.uuid,
+        Sha1::parse("b075fb2acc9573f8b9546522f2c7f2221a062a29").unwrap(),
+    )
+    .unwrap();
+    let head = TR_BASIC.open().head_commit;
+    let fs = SeafFuse::new(lib);
+    let old_attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+    assert!(fs.do_lookup(FUSE_ROOT_ID, OsStr::new("somedir")).is_err());
+
+    let changed = fs.switch_commit(head.clone()).unwrap();
+
+    assert_eq!(changed, ["somedir", "test.md"]);
+    assert_eq!(fs.commit(), head);
+    assert!(fs.do_lookup(FUSE_ROOT_ID, OsStr::new("somedir")).is_ok());
+
+    // The old version of the file stays accessible through its inode
+    assert_eq!(fs.do_getattr(old_attr.ino).unwrap(), old_attr);
+}
+
+#[test]
+fn refresh_picks_up_new_commits() {
+    let tmp = TR_BASIC.copy();
+    let newest = "a47ac095b65fdfb64dd751570219036202577f0c";
+    let newest_path = tmp
+        .path()
+        .join("commits")
+        .join(TR_BASIC.uuid)
+        .join(&newest[..2])
+        .join(&newest[2..]);
+    let saved = fs::read(&newest_path).unwrap();
+    fs::remove_file(&newest_path).unwrap();
+
+    let fs = SeafFuse::new(Library::open(tmp.path(), TR_BASIC.uuid).unwrap());
+    let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+    let fh = fs.do_open(attr.ino, O_RDONLY).unwrap();
+    assert_eq!(fs.refresh().unwrap(), None);
+
+    fs::write(&newest_path, saved).unwrap();
+
+    assert_eq!(fs.refresh().unwrap().unwrap(), ["somedir"]);
+    assert_eq!(fs.commit().commit_id.to_string(), newest);
+    assert_eq!(fs.do_read(fh, 8, 4).unwrap(), b"test");
+}
+
+#[test]
+fn switch_commit_keeps_inode_of_new_root() {
+    let lib = TR_BASIC.open();
+    let head = lib.head_commit.clone();
+    let somedir_id = lib.resolve_path(Path::new("somedir")).unwrap().unwrap();
+    let fs = SeafFuse::new(lib);
+    let somedir = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("somedir")).unwrap();
+
+    fs.switch_commit(CommitJson {
+        root_id: somedir_id,
+        ..head
+    })
+    .unwrap();
+
+    // The same dir is now both the root and an entry of the old root, under two inodes
+    assert_eq!(fs.do_getattr(somedir.ino).unwrap(), somedir);
+    assert!(fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test2.md")).is_ok());
+}
+
+#[test]
+fn refresh_only_reads_new_commits() {
+    let tmp = TR_BASIC.copy();
+    let fs = SeafFuse::new(Library::open(tmp.path(), TR_BASIC.uuid).unwrap());
+    assert_eq!(fs.refresh().unwrap(), None);
+
+    // Commits already seen are not read again
+    let oldest = "038cac5ffc20b13a4fac8d21e60bf01d03f8a179";
+    let oldest_path = tmp
+        .path()
+        .join("commits")
+        .join(TR_BASIC.uuid)
+        .join(&oldest[..2])
+        .join(&oldest[2..]);
+    fs::write(&oldest_path, b"garbage").unwrap();
+
+    assert_eq!(fs.refresh().unwrap(), None);
+}
+
+const UUID_A: &str = "aaaaaaaa-0000-4000-8000-000000000001";
+const UUID_B: &str = "bbbbbbbb-0000-4000-8000-000000000002";
+const UUID_C: &str = "cccccccc-0000-4000-8000-000000000003";
+
+fn mount_all(root: &Path) -> MultiFuse {
+    MultiFuse::new(find_libraries(root).unwrap(), |lib| lib)
+}
+
+#[test]
+fn find_libraries_in_storage_root() {
+    let root = storage_root(&[(&TR_BASIC, UUID_A), (&TR_NESTED, UUID_B)]);
+    let libraries = find_libraries(root.path()).unwrap();
+    let names: Vec<&str> = libraries
+        .iter()
+        .map(|l| l.recent_commit.repo_name.as_str())
+        .collect();
+
+    assert_eq!(names, ["Test", "Nested"]);
+    assert!(!libraries[0].recent_commit.encrypted);
+}
+
+#[test]
+fn mount_all_lists_libraries_without_opening_them() {
+    let root = storage_root(&[(&TR_BASIC, UUID_A), (&TR_NESTED, UUID_B)]);
+    let fs = mount_all(root.path());
+    let mut names: Vec<OsString> = fs
+        .do_readdir(FUSE_ROOT_ID)
+        .unwrap()
+        .into_iter()
+        .map(|e| e.name)
+        .collect();
+    names.sort();
+
+    assert_eq!(names, ["Nested", "Test"]);
+
+    let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("Test")).unwrap();
+    assert_eq!(fs.do_getattr(attr.ino).unwrap(), attr);
+    assert_eq!(fs.opened_count(), 0);
+}
+
+#[test]
+fn mount_all_opens_libraries_at_their_current_head() {
+    let root = storage_root(&[(&TR_BASIC, UUID_A)]);
+    let newest = "a47ac095b65fdfb64dd751570219036202577f0c";
+    let newest_path = root
+        .path()
+        .join("commits")
+        .join(UUID_A)
+        .join(&newest[..2])
+        .join(&newest[2..]);
+    let saved = fs::read(&newest_path).unwrap();
+    fs::remove_file(&newest_path).unwrap();
+
+    let fs = mount_all(root.path());
+    fs::write(&newest_path, saved).unwrap();
+
+    let lib = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("Test")).unwrap();
+    assert!(fs.do_lookup(lib.ino, OsStr::new("somedir")).is_ok());
+}
+
+#[test]
+fn mount_all_statfs_counts_opened_libraries() {
+    let root = storage_root(&[(&TR_BASIC, UUID_A), (&TR_NESTED, UUID_B)]);
+    let fs = mount_all(root.path()).with_statfs(StatfsSource::Blocks);
+    assert_eq!(fs.do_statfs().unwrap(), UsageStats::default());
+
+    let lib = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("Test")).unwrap();
+    fs.do_readdir(lib.ino).unwrap();
+
+    assert_eq!(fs.do_statfs().unwrap().bytes, 33);
+    assert_eq!(fs.opened_count(), 1);
+}
+
+#[test]
+fn mount_all_reads_files() {
+    let root = storage_root(&[(&TR_BASIC, UUID_A), (&TR_NESTED, UUID_B)]);
+    let fs = mount_all(root.path());
+    let lib = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("Test")).unwrap();
+    let attr = fs.do_lookup(lib.ino, OsStr::new("test.md")).unwrap();
+    let fh = fs.do_open(attr.ino, O_RDONLY).unwrap();
+
+    assert_eq!(fs.do_read(fh, 0, 1024).unwrap(), b"# test\n\ntest\n");
+    assert_eq!(fs.do_getattr(attr.ino).unwrap(), attr);
+    assert_eq!(fs.opened_count(), 1);
+
+    fs.do_release(fh).unwrap();
+}
+
+#[test]
+fn mount_all_inodes_are_distinct() {
+    let root = storage_root(&[(&TR_BASIC, UUID_A), (&TR_BASIC, UUID_B)]);
+    let fs = mount_all(root.path());
+    let names = fs.names();
+
+    assert_eq!(names, ["Test (aaaaaaaa)", "Test (bbbbbbbb)"]);
+
+    let inos: Vec<u64> = names
+        .iter()
+        .map(|name| {
+            let lib = fs.do_lookup(FUSE_ROOT_ID, name).unwrap();
+            let fh = fs.do_opendir(lib.ino).unwrap();
+            let entries = fs.do_dir_entries(fh).unwrap();
+            let d = entries.iter().find(|d| d.name == "test.md").unwrap();
+            fs.do_ref_dentry(fh, d).ino
+        })
+        .collect();
+
+    assert_ne!(inos[0], inos[1]);
+    assert_eq!(fs.do_getattr(inos[0]).unwrap().size, 13);
+    assert_eq!(fs.do_getattr(inos[1]).unwrap().size, 13);
+}
+
+#[test]
+fn mount_all_encrypted_library_is_inaccessible() {
+    let root = storage_root(&[(&TR_BASIC, UUID_A), (&TR_NESTED, UUID_C)]);
+    mark_encrypted(root.path(), UUID_C);
+
+    let fs = mount_all(root.path());
+    let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("Nested")).unwrap();
+
+    assert_eq!(attr.perm, 0);
+    assert_eq!(fs.do_opendir(attr.ino).unwrap_err(), EACCES);
+    assert_eq!(
+        fs.do_lookup(attr.ino, OsStr::new("a.md")).unwrap_err(),
+        EACCES
+    );
+
+    let test = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("Test")).unwrap();
+    assert!(fs.do_lookup(test.ino, OsStr::new("test.md")).is_ok());
+}
+
+#[test]
+fn xattrs_of_file() {
+    let lib = TR_BASIC.open();
+    let fs = SeafFuse::new(lib.clone());
+    let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+    let get = |name: &str| String::from_utf8(fs.do_getxattr(attr.ino, OsStr::new(name)).unwrap());
+
+    let id = get(XATTR_ID).unwrap();
+    let file = lib.file_by_id(Sha1::parse(&id).unwrap()).unwrap();
+    let blocks: String = file.block_ids.iter().map(|b| format!("{b}\n")).collect();
+
+    assert_eq!(id, "e40b894880747010bf6ec384b83e578f352beed7");
+    assert_eq!(get(XATTR_BLOCKS).unwrap(), blocks);
+    assert_eq!(
+        get(XATTR_COMMIT).unwrap(),
+        "a47ac095b65fdfb64dd751570219036202577f0c"
+    );
+    assert_eq!(get(XATTR_REPO_ID).unwrap(), TR_BASIC.uuid);
+    assert_eq!(get(XATTR_MODIFIER).unwrap(), "johan@forberg.se");
+}
+
+#[test]
+fn xattr_commit_is_where_the_inode_was_found() {
+    let fs = SeafFuse::new(TR_BASIC.open());
+    let head = fs.commit().commit_id.to_string();
+    let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("somedir")).unwrap();
+    let commit = |ino| fs.do_getxattr(ino, OsStr::new(XATTR_COMMIT)).unwrap();
+
+    let old = "b075fb2acc9573f8b9546522f2c7f2221a062a29";
+    let lib = TR_BASIC.open();
+    fs.switch_commit(lib.commit_by_id(Sha1::parse(old).unwrap()).unwrap())
+        .unwrap();
+
+    assert_eq!(commit(attr.ino), head.as_bytes());
+    assert_eq!(commit(FUSE_ROOT_ID), old.as_bytes());
+}
+
+#[test]
+fn xattrs_of_dir() {
+    let fs = SeafFuse::new(TR_BASIC.open());
+    let attr = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("somedir")).unwrap();
+    let names = fs.do_listxattr(attr.ino).unwrap();
+
+    assert_eq!(
+        names,
+        b"user.seafile.id\0user.seafile.commit\0user.seafile.repo_id\0"
+    );
+    assert_eq!(
+        fs.do_getxattr(attr.ino, OsStr::new(XATTR_BLOCKS))
+            .unwrap_err(),
+        ENODATA
+    );
+}
+
+#[test]
+fn statfs_follows_commit() {
+    let lib = Library::open_for_commit(
+        Path::new(TR_BASIC.path),
+        TR_BASIC.uuid,
+        Sha1::parse("b075fb2acc9573f8b9546522f2c7f2221a062a29").unwrap(),
+    )
+    .unwrap();
+    let head = TR_BASIC.open().head_commit;
+    let fs = SeafFuse::new(lib.clone());
+
+    assert_eq!(
+        fs.do_statfs().unwrap(),
+        lib.tree_usage(fs.commit().root_id).unwrap()
+    );
+
+    fs.switch_commit(head).unwrap();
+
+    assert_eq!(
+        fs.do_statfs().unwrap(),
+        UsageStats {
+            bytes: 33,
+            files: 2,
+            dirs: 2
+        }
+    );
+}
+
+#[test]
+fn statfs_of_blocks() {
+    let fs = SeafFuse::new(TR_BASIC.open()).with_statfs(StatfsSource::Blocks);
+
+    assert_eq!(fs.do_statfs().unwrap().bytes, 33);
+}
+
+fn read_control_file(fs: &SeafFuse, name: &str) -> String {
+    let dir = fs
+        .do_lookup(FUSE_ROOT_ID, OsStr::new(CONTROL_DIR_NAME))
+        .unwrap();
+    let attr = fs.do_lookup(dir.ino, OsStr::new(name)).unwrap();
+    let fh = fs.do_open(attr.ino, O_RDONLY).unwrap();
+    let data = fs.do_read(fh, 0, 1 << 16).unwrap();
+    fs.do_release(fh).unwrap();
+
+    String::from_utf8(data).unwrap()
+}
+
+fn write_ctl(fs: &SeafFuse, command: &str) -> Result<u32, i32> {
+    let dir = fs
+        .do_lookup(FUSE_ROOT_ID, OsStr::new(CONTROL_DIR_NAME))
+        .unwrap();
+    let attr = fs.do_lookup(dir.ino, OsStr::new("ctl")).unwrap();
+    let fh = fs.do_open(attr.ino, O_WRONLY | O_TRUNC).unwrap();
+    let r = fs.do_write(fh, command.as_bytes());
+    fs.do_release(fh).unwrap();
+
+    r
+}
+
+#[test]
+fn control_dir_is_hidden() {
+    let fs = SeafFuse::new(TR_BASIC.open());
+    assert_eq!(
+        fs.do_lookup(FUSE_ROOT_ID, OsStr::new(CONTROL_DIR_NAME))
+            .unwrap_err(),
+        ENOENT
+    );
+
+    let fs = SeafFuse::new(TR_BASIC.open()).with_control_dir(true);
+    let dir = fs
+        .do_lookup(FUSE_ROOT_ID, OsStr::new(CONTROL_DIR_NAME))
+        .unwrap();
+    let root: Vec<OsString> = fs
+        .do_readdir(FUSE_ROOT_ID)
+        .unwrap()
+        .into_iter()
+        .map(|d| d.name)
+        .collect();
+    let control: Vec<OsString> = fs
+        .do_readdir(dir.ino)
+        .unwrap()
+        .into_iter()
+        .map(|d| d.name)
+        .collect();
+
+    assert!(!root.contains(&CONTROL_DIR_NAME.into()));
+    assert_eq!(control, ["commit.json", "library.json", "stats", "ctl"]);
+    assert!(fs.do_direct_io(fs.do_lookup(dir.ino, OsStr::new("stats")).unwrap().ino));
+}
+
+#[test]
+fn control_files() {
+    let fs = SeafFuse::new(TR_BASIC.open()).with_control_dir(true);
+    let commit: serde_json::Value =
+        serde_json::from_str(&read_control_file(&fs, "commit.json")).unwrap();
+    let library: serde_json::Value =
+        serde_json::from_str(&read_control_file(&fs, "library.json")).unwrap();
+    let stats = read_control_file(&fs, "stats");
+
+    assert_eq!(
+        commit["commit_id"],
+        "a47ac095b65fdfb64dd751570219036202577f0c"
+    );
+    assert_eq!(library["repo_id"], TR_BASIC.uuid);
+    assert_eq!(library["repo_name"], "Test");
+    assert!(stats.lines().any(|l| l.starts_with("fs_cache.hits ")));
+    assert!(read_control_file(&fs, "ctl").contains("checkout"));
+}
+
+#[test]
+fn control_checkout() {
+    let fs = SeafFuse::new(TR_BASIC.open()).with_control_dir(true);
+
+    write_ctl(&fs, "checkout b075fb2acc9573f8b9546522f2c7f2221a062a29\n").unwrap();
+    assert_eq!(
+        fs.commit().commit_id.to_string(),
+        "b075fb2acc9573f8b9546522f2c7f2221a062a29"
+    );
+    assert!(fs.do_lookup(FUSE_ROOT_ID, OsStr::new("somedir")).is_err());
+    assert!(read_control_file(&fs, "commit.json").contains("b075fb2a"));
+
+    write_ctl(&fs, "checkout 2025-02-01T15:35:24Z").unwrap();
+    assert_eq!(
+        fs.commit().commit_id.to_string(),
+        "038cac5ffc20b13a4fac8d21e60bf01d03f8a179"
+    );
+
+    write_ctl(&fs, "refresh").unwrap();
+    assert_eq!(
+        fs.commit().commit_id.to_string(),
+        "a47ac095b65fdfb64dd751570219036202577f0c"
+    );
+    assert!(fs.do_lookup(FUSE_ROOT_ID, OsStr::new("somedir")).is_ok());
+
+    assert_eq!(write_ctl(&fs, "checkout 0"), Err(ENOENT));
+    assert_eq!(write_ctl(&fs, "frobnicate"), Err(EINVAL));
+}
+
+#[test]
+fn control_write_permissions() {
+    let fs = SeafFuse::new(TR_BASIC.open()).with_control_dir(true);
+    let dir = fs
+        .do_lookup(FUSE_ROOT_ID, OsStr::new(CONTROL_DIR_NAME))
+        .unwrap();
+    let stats = fs.do_lookup(dir.ino, OsStr::new("stats")).unwrap();
+    let ctl = fs.do_lookup(dir.ino, OsStr::new("ctl")).unwrap();
+    let file = fs.do_lookup(FUSE_ROOT_ID, OsStr::new("test.md")).unwrap();
+
+    assert_eq!(fs.do_open(stats.ino, O_WRONLY).unwrap_err(), EACCES);
+    assert_eq!(fs.do_open(file.ino, O_RDWR).unwrap_err(), EROFS);
+
+    let fh = fs.do_open(ctl.ino, O_RDONLY).unwrap();
+    assert_eq!(fs.do_write(fh, b"refresh").unwrap_err(), EBADF);
+}
+
+#[test]
+fn parse_control_commands() {
+    assert_eq!(
+        ControlCommand::parse(" refresh ").unwrap(),
+        ControlCommand::Refresh
+    );
+    assert_eq!(
+        ControlCommand::parse("checkout 1738424108").unwrap(),
+        ControlCommand::CheckoutTime(1738424108)
+    );
+    assert_eq!(
+        ControlCommand::parse("checkout a47ac095b65fdfb64dd751570219036202577f0c").unwrap(),
+        ControlCommand::CheckoutId(
+            Sha1::parse("a47ac095b65fdfb64dd751570219036202577f0c").unwrap()
+        )
+    );
+    assert!(ControlCommand::parse("checkout a47ac095").is_err());
+    assert!(ControlCommand::parse("refresh now").is_err());
+}
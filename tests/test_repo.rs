@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: MIT
 
 use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
 use std::io;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
@@ -212,11 +214,89 @@ fn open_nonexistent_file() {
     let id = Sha1::parse("1234123412341234123412341234123412341234").unwrap();
 
     match lib.file_by_id(id) {
-        Err(SeafError::IO(_, e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+        Err(SeafError::ObjectMissing(obj)) => {
+            assert_eq!(obj.ty, ObjType::Fs);
+            assert_eq!(obj.id, id);
+            assert_eq!(obj.uuid, TR_BASIC.uuid);
+        }
         _ => unreachable!(),
     };
 }
 
+#[test]
+fn error_reports_object_and_path() {
+    let tmp = TR_BASIC.copy();
+    let obj_path = tmp
+        .path()
+        .join("fs")
+        .join(TR_BASIC.uuid)
+        .join("e4/0b894880747010bf6ec384b83e578f352beed7");
+    fs::write(&obj_path, "garbage").unwrap();
+    let lib = Library::open(tmp.path(), TR_BASIC.uuid).unwrap();
+
+    let e = lib.fs_iterator().find_map(|r| r.err()).unwrap();
+    let SeafError::Context(path, inner) = &e else {
+        panic!("No context: {e:?}");
+    };
+    assert_eq!(path, "test.md");
+    let SeafError::CorruptObject(obj, _) = inner.as_ref() else {
+        panic!("Not corrupt: {inner:?}");
+    };
+    assert_eq!(obj.ty, ObjType::Fs);
+    assert_eq!(obj.path, obj_path);
+
+    assert_eq!(e.errno(), libc::EIO);
+    assert!(e.source().unwrap().source().is_some());
+    assert!(e.report().starts_with(&format!(
+        "test.md: Corrupt fs object e40b894880747010bf6ec384b83e578f352beed7 of library {} (",
+        TR_BASIC.uuid
+    )));
+}
+
+#[test]
+fn fs_iterator_stops_without_root() {
+    let tmp = TR_BASIC.copy();
+    let lib = Library::open(tmp.path(), TR_BASIC.uuid).unwrap();
+    let root_id = lib.head_commit.root_id.to_string();
+    fs::remove_file(
+        tmp.path()
+            .join("fs")
+            .join(TR_BASIC.uuid)
+            .join(&root_id[..2])
+            .join(&root_id[2..]),
+    )
+    .unwrap();
+
+    let mut it = lib.fs_iterator();
+    assert!(it.next().unwrap().is_err());
+    assert!(it.next().is_none());
+}
+
+#[test]
+fn open_missing_library() {
+    let e = Library::open(Path::new(TR_BASIC.path), "nope").unwrap_err();
+
+    assert!(matches!(e, SeafError::NotFound(_)));
+    assert_eq!(
+        e.to_string(),
+        "Library nope in tests/testrepos/basic not found"
+    );
+}
+
+#[test]
+fn encrypted_files_cannot_be_read() {
+    let tmp = TR_BASIC.copy();
+    mark_encrypted(tmp.path(), TR_BASIC.uuid);
+    let lib = Library::open(tmp.path(), TR_BASIC.uuid).unwrap();
+    let id = lib.resolve_path(Path::new("test.md")).unwrap().unwrap();
+
+    assert!(lib.file_by_id(id).is_ok());
+    assert!(matches!(
+        lib.file_reader_by_id(id),
+        Err(SeafError::Encrypted(_))
+    ));
+}
+
 #[test]
 fn empty_root_dir() {
     let lib = TR_EMPTY_DIR.open();
@@ -224,3 +304,380 @@ fn empty_root_dir() {
     let dir = lib.load_fs(lib.head_commit.root_id).unwrap().unwrap_dir();
     assert_eq!(dir.dirents, vec![]);
 }
+
+#[test]
+fn fs_cache_hits_and_misses() {
+    let lib = TR_BASIC.open();
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+
+    let f1 = lib.file_by_id(id).unwrap();
+    let f2 = lib.file_by_id(id).unwrap();
+    assert_eq!(f1, f2);
+
+    let stats = lib.fs_cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+}
+
+#[test]
+fn fs_cache_evicts_least_recently_used() {
+    let lib = TR_BASIC.open().with_fs_cache_size(1);
+    let file_id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+    let dir_id = Sha1::parse("ebd03d7c735be353d1c6d302e1092e69b5c5d041").unwrap();
+
+    lib.load_fs(file_id).unwrap();
+    lib.load_fs(dir_id).unwrap();
+    lib.load_fs(file_id).unwrap();
+
+    let stats = lib.fs_cache_stats();
+    assert_eq!((stats.hits, stats.misses), (0, 3));
+    assert_eq!((stats.entries, stats.capacity), (1, 1));
+}
+
+#[test]
+fn fs_cache_disabled() {
+    let lib = TR_BASIC.open().with_fs_cache_size(0);
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+
+    lib.load_fs(id).unwrap();
+    lib.load_fs(id).unwrap();
+
+    let stats = lib.fs_cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (0, 2, 0));
+}
+
+#[test]
+fn block_files_stay_open_between_reads() {
+    let lib = TR_MULTIBLOCK.open();
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+    let f = lib.file_by_id(id).unwrap();
+    let fr = lib.file_reader(&f).unwrap();
+    let mut bytes = [0; 1];
+
+    for i in 0..17 {
+        fr.read_at(i, &mut bytes).unwrap();
+    }
+
+    // 17 reads plus sizing the first two blocks, but each block is only opened once
+    let stats = lib.block_file_cache_stats();
+    assert_eq!((stats.misses, stats.hits), (3, 16));
+    assert_eq!(stats.entries, 3);
+}
+
+#[test]
+fn block_file_limit_is_respected() {
+    let lib = TR_MULTIBLOCK.open().with_max_open_files(1);
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+    let f = lib.file_by_id(id).unwrap();
+    let mut fr = lib.file_reader(&f).unwrap();
+    let mut bytes = vec![];
+
+    fr.read_to_end(&mut bytes).unwrap();
+
+    assert_eq!(&bytes, b"gronkadonkachonka");
+    assert_eq!(lib.block_file_cache_stats().entries, 1);
+}
+
+#[test]
+fn open_file_with_missing_block() {
+    let tmp = TR_MULTIBLOCK.copy();
+    let lib = Library::open(tmp.path(), TR_MULTIBLOCK.uuid).unwrap();
+    fs::remove_file(
+        tmp.path()
+            .join("blocks")
+            .join(TR_MULTIBLOCK.uuid)
+            .join("fc/1776a9f030e90685ee2c5ceba8c0f934f98e02"),
+    )
+    .unwrap();
+
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+    let fr = lib.file_reader_by_id(id).unwrap();
+    let mut bytes = [0; 6];
+
+    assert_eq!(fr.read_at(0, &mut bytes).unwrap(), 6);
+    assert_eq!(&bytes, b"gronka");
+
+    let e = fr.read_at(6, &mut bytes).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn errors_map_to_errno() {
+    let path = PathBuf::from("x");
+    let io_error = |e: io::Error| SeafError::IO(path.clone(), e).errno();
+
+    // Only errors from looking up a path keep their errno
+    assert_eq!(
+        io_error(io::Error::from_raw_os_error(libc::EMFILE)),
+        libc::EIO
+    );
+    assert_eq!(
+        io_error(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        libc::ENOTDIR
+    );
+    assert_eq!(io_error(io::ErrorKind::NotFound.into()), libc::ENOENT);
+    assert_eq!(
+        io_error(io::ErrorKind::PermissionDenied.into()),
+        libc::EACCES
+    );
+    assert_eq!(io_error(io::ErrorKind::UnexpectedEof.into()), libc::EIO);
+    assert_eq!(SeafError::NoHeadCommit.errno(), libc::ENOENT);
+    assert_eq!(SeafError::WrongFsType.errno(), libc::ENOTDIR);
+    assert_eq!(SeafError::NotImpl.errno(), libc::ENOSYS);
+
+    let e = parse_fs_json(Path::new("tests/testrepos/nope")).unwrap_err();
+    assert_eq!(e.errno(), libc::ENOENT);
+    let e = parse_fs_json(Path::new("Cargo.toml")).unwrap_err();
+    assert_eq!(e.errno(), libc::EIO);
+}
+
+#[test]
+fn seek_past_blocks_only_sizes_earlier_blocks() {
+    let lib = TR_MULTIBLOCK.open();
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+    let fr = lib.file_reader_by_id(id).unwrap();
+    let mut bytes = [0; 3];
+
+    // Only the first block needs to be sized to find the second
+    assert_eq!(fr.read_at(6, &mut bytes).unwrap(), 3);
+    assert_eq!(&bytes, b"don");
+    assert_eq!(lib.block_file_cache_stats().entries, 2);
+}
+
+#[test]
+fn block_layout_is_reused_across_opens() {
+    let lib = TR_MULTIBLOCK.open().with_max_open_files(0);
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+    let mut bytes = [0; 6];
+
+    let fr = lib.file_reader_by_id(id).unwrap();
+    fr.read_at(11, &mut bytes).unwrap();
+    assert_eq!(&bytes, b"chonka");
+    let first_open_lookups = lib.block_file_cache_stats().misses;
+
+    let fr = lib.file_reader_by_id(id).unwrap();
+    fr.read_at(11, &mut bytes).unwrap();
+    assert_eq!(&bytes, b"chonka");
+    let second_open_lookups = lib.block_file_cache_stats().misses - first_open_lookups;
+
+    // The first open has to size the two leading blocks, the second only reads the last one
+    assert_eq!((first_open_lookups, second_open_lookups), (3, 1));
+}
+
+#[test]
+fn sequential_reads_prefetch_following_blocks() {
+    let lib = TR_MULTIBLOCK.open().with_readahead(2, 4);
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+    let mut fr = lib.file_reader_by_id(id).unwrap();
+    let mut bytes = [0; 3];
+
+    fr.read_at(0, &mut bytes).unwrap();
+    fr.read_at(3, &mut bytes).unwrap();
+    lib.wait_for_prefetch();
+    assert_eq!(lib.block_cache_stats().entries, 2);
+
+    let mut rest = vec![];
+    fr.seek(SeekFrom::Start(6)).unwrap();
+    fr.read_to_end(&mut rest).unwrap();
+
+    assert_eq!(&rest, b"donkachonka");
+    assert_eq!(lib.block_cache_stats().hits, 2);
+}
+
+#[test]
+fn random_reads_do_not_prefetch() {
+    let lib = TR_MULTIBLOCK.open().with_readahead(2, 4);
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+    let fr = lib.file_reader_by_id(id).unwrap();
+    let mut bytes = [0; 3];
+
+    fr.read_at(14, &mut bytes).unwrap();
+    fr.read_at(0, &mut bytes).unwrap();
+    fr.read_at(8, &mut bytes).unwrap();
+    lib.wait_for_prefetch();
+
+    assert_eq!(lib.block_cache_stats().entries, 0);
+}
+
+#[test]
+fn forward_random_reads_do_not_prefetch() {
+    let lib = TR_MULTIBLOCK.open().with_readahead(2, 4);
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+    let fr = lib.file_reader_by_id(id).unwrap();
+    let mut bytes = [0; 2];
+
+    fr.read_at(0, &mut bytes).unwrap();
+    fr.read_at(5, &mut bytes).unwrap();
+    fr.read_at(9, &mut bytes).unwrap();
+    lib.wait_for_prefetch();
+
+    assert_eq!(lib.block_cache_stats().entries, 0);
+}
+
+#[test]
+fn lookup_dirent_by_name() {
+    let lib = TR_BASIC.open();
+    let root_id = lib.head_commit.root_id;
+
+    let de = lib.lookup_dirent(root_id, "test.md").unwrap().unwrap();
+    assert_eq!(
+        de.id.to_string(),
+        "e40b894880747010bf6ec384b83e578f352beed7"
+    );
+    assert_eq!(lib.lookup_dirent(root_id, "nope").unwrap(), None);
+}
+
+#[test]
+fn lookup_dirent_in_file() {
+    let lib = TR_BASIC.open();
+    let id = Sha1::parse("e40b894880747010bf6ec384b83e578f352beed7").unwrap();
+
+    assert!(matches!(
+        lib.lookup_dirent(id, "x"),
+        Err(SeafError::WrongFsType)
+    ));
+}
+
+#[test]
+fn resolve_nested_paths() {
+    let lib = TR_NESTED.open();
+    let root_id = lib.head_commit.root_id;
+
+    assert_eq!(lib.resolve_path(Path::new("")).unwrap(), Some(root_id));
+
+    let b = lib.resolve_path(Path::new("b")).unwrap().unwrap();
+    let b_md = lib.resolve_path(Path::new("b/b.md")).unwrap().unwrap();
+    assert_eq!(
+        lib.resolve_path(Path::new("/b/./b.md")).unwrap(),
+        Some(b_md)
+    );
+    assert_eq!(lib.lookup_dirent(b, "b.md").unwrap().unwrap().id, b_md);
+
+    assert_eq!(lib.resolve_path(Path::new("b/nope")).unwrap(), None);
+    assert_eq!(lib.resolve_path(Path::new("b/../b")).unwrap(), None);
+    assert_eq!(lib.resolve_path(Path::new("b/b.md/x")).unwrap(), None);
+}
+
+#[test]
+fn lookup_duplicate_names() {
+    let tmp = TR_BASIC.copy();
+    let dir_id = "1111111111111111111111111111111111111111";
+    let first = "e40b894880747010bf6ec384b83e578f352beed7";
+    let second = "2222222222222222222222222222222222222222";
+    TR_BASIC.write_dirents(
+        tmp.path(),
+        dir_id,
+        &[("a.md", first), ("A.md", second), ("a.md", second)],
+    );
+    let dir = Sha1::parse(dir_id).unwrap();
+
+    // The first entry of a name wins, both for exact lookups and ones matching by case
+    for case_insensitive in [false, true] {
+        let lib = Library::open(tmp.path(), TR_BASIC.uuid)
+            .unwrap()
+            .with_name_matching(NameMatching {
+                case_insensitive,
+                normalize: Normalization::None,
+            });
+        let id = |name| {
+            lib.lookup_dirent(dir, name)
+                .unwrap()
+                .unwrap()
+                .id
+                .to_string()
+        };
+        assert_eq!(id("a.md"), first);
+        assert_eq!(id("A.md"), second);
+    }
+}
+
+#[test]
+fn lookup_dirent_with_name_matching() {
+    let tmp = TR_BASIC.copy();
+    let dir_id = "1111111111111111111111111111111111111111";
+    let file_id = "e40b894880747010bf6ec384b83e578f352beed7";
+    // Stored out of order, and with the decomposed é written by macOS
+    TR_BASIC.write_dir(
+        tmp.path(),
+        dir_id,
+        &["readme.md", "README.md", "Cafe\u{301}.txt"],
+        file_id,
+    );
+    let dir = Sha1::parse(dir_id).unwrap();
+    let open = |case_insensitive, normalize| {
+        Library::open(tmp.path(), TR_BASIC.uuid)
+            .unwrap()
+            .with_name_matching(NameMatching {
+                case_insensitive,
+                normalize,
+            })
+    };
+    let name = |lib: &Library, name| lib.lookup_dirent(dir, name).unwrap().map(|de| de.name);
+
+    let lib = open(false, Normalization::None);
+    assert_eq!(name(&lib, "readme.md").unwrap(), "readme.md");
+    assert_eq!(name(&lib, "Readme.md"), None);
+    assert_eq!(name(&lib, "Caf\u{e9}.txt"), None);
+
+    let lib = open(true, Normalization::None);
+    assert_eq!(name(&lib, "readme.md").unwrap(), "readme.md");
+    assert_eq!(name(&lib, "Readme.MD").unwrap(), "README.md");
+    assert_eq!(name(&lib, "Caf\u{e9}.txt"), None);
+
+    for normalize in [Normalization::Nfc, Normalization::Nfd] {
+        let lib = open(false, normalize);
+        assert_eq!(name(&lib, "Caf\u{e9}.txt").unwrap(), "Cafe\u{301}.txt");
+        assert_eq!(name(&lib, "caf\u{e9}.txt"), None);
+    }
+
+    let lib = open(true, Normalization::Nfc);
+    assert_eq!(name(&lib, "CAF\u{c9}.TXT").unwrap(), "Cafe\u{301}.txt");
+}
+
+#[test]
+fn tree_usage() {
+    let lib = TR_BASIC.open();
+    let usage = lib.tree_usage(lib.head_commit.root_id).unwrap();
+
+    assert_eq!(
+        usage,
+        UsageStats {
+            bytes: 33,
+            files: 2,
+            dirs: 2
+        }
+    );
+}
+
+#[test]
+fn block_usage() {
+    let usage = TR_BASIC.open().block_usage().unwrap();
+
+    assert_eq!(
+        usage,
+        UsageStats {
+            bytes: 33,
+            files: 2,
+            dirs: 0
+        }
+    );
+}
+
+#[test]
+fn commit_at_time() {
+    let lib = TR_BASIC.open();
+    let id = |c: Option<CommitJson>| c.map(|c| c.commit_id.to_string());
+
+    assert_eq!(lib.commit_at(1738424097).unwrap(), None);
+    assert_eq!(
+        id(lib.commit_at(1738424108).unwrap()).unwrap(),
+        "b075fb2acc9573f8b9546522f2c7f2221a062a29"
+    );
+    assert_eq!(
+        id(lib.commit_at(1738424123).unwrap()).unwrap(),
+        "b075fb2acc9573f8b9546522f2c7f2221a062a29"
+    );
+    assert_eq!(
+        id(lib.commit_at(u64::MAX).unwrap()).unwrap(),
+        "a47ac095b65fdfb64dd751570219036202577f0c"
+    );
+}
@@ -1,5 +1,10 @@
+use flate2::write::ZlibEncoder;
 use seafuse::*;
+use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempdir::TempDir;
 
 pub struct TestRepo {
     pub path: &'static str,
@@ -15,9 +20,111 @@ impl TestRepo {
             .join(&uuid[2..])
     }
 
+    /// Path of an object in the copy of the repo at `root`
+    pub fn path_in(&self, root: &Path, ty: &str, id: &str) -> PathBuf {
+        root.join(self.path_to(ty, id).strip_prefix(self.path).unwrap())
+    }
+
     pub fn open(&self) -> Library {
         Library::open(Path::new(self.path), self.uuid).unwrap()
     }
+
+    /// Make a private copy of the repo, for tests which need to modify it
+    pub fn copy(&self) -> TempDir {
+        let tmp = TempDir::new("seafuse-repo").unwrap();
+
+        for e in walkdir::WalkDir::new(self.path) {
+            let e = e.unwrap();
+            let dest = tmp.path().join(e.path().strip_prefix(self.path).unwrap());
+
+            if e.file_type().is_dir() {
+                fs::create_dir_all(dest).unwrap();
+            } else {
+                fs::copy(e.path(), dest).unwrap();
+            }
+        }
+
+        tmp
+    }
+
+    /// Copy the library into the storage root `dest` under a different UUID
+    pub fn copy_as(&self, dest: &Path, uuid: &str) {
+        for e in walkdir::WalkDir::new(self.path) {
+            let e = e.unwrap();
+            let rel: PathBuf = e
+                .path()
+                .strip_prefix(self.path)
+                .unwrap()
+                .iter()
+                .map(|c| if c == self.uuid { uuid.as_ref() } else { c })
+                .collect();
+            let dest = dest.join(rel);
+
+            if e.file_type().is_dir() {
+                fs::create_dir_all(dest).unwrap();
+            } else {
+                fs::copy(e.path(), dest).unwrap();
+            }
+        }
+    }
+
+    /// Write a dir object holding `names`, all pointing at the file `file_id`, into the copy of
+    /// the repo at `root`
+    pub fn write_dir(&self, root: &Path, dir_id: &str, names: &[&str], file_id: &str) {
+        let entries: Vec<_> = names.iter().map(|name| (*name, file_id)).collect();
+        self.write_dirents(root, dir_id, &entries);
+    }
+
+    /// Write a dir object holding the given names and ids, in that order, into the copy of the
+    /// repo at `root`
+    pub fn write_dirents(&self, root: &Path, dir_id: &str, entries: &[(&str, &str)]) {
+        let dirents: Vec<_> = entries
+            .iter()
+            .map(|(name, file_id)| {
+                serde_json::json!({
+                    "id": file_id,
+                    "mode": 0o100644,
+                    "mtime": 1738424108,
+                    "name": name,
+                })
+            })
+            .collect();
+        let dir = serde_json::json!({"dirents": dirents, "type": 3, "version": 1});
+
+        let path = self.path_in(root, "fs", dir_id);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut enc = ZlibEncoder::new(fs::File::create(path).unwrap(), Default::default());
+        enc.write_all(dir.to_string().as_bytes()).unwrap();
+        enc.finish().unwrap();
+    }
+}
+
+/// The seafuse binary, ready to be given arguments
+pub fn seafuse() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_seafuse"))
+}
+
+/// Make a storage root holding the given libraries, each under its own UUID
+pub fn storage_root(libraries: &[(&TestRepo, &str)]) -> TempDir {
+    let tmp = TempDir::new("seafuse-storage").unwrap();
+
+    for (repo, uuid) in libraries {
+        repo.copy_as(tmp.path(), uuid);
+    }
+
+    tmp
+}
+
+/// Mark every commit of the library `uuid` in the storage root `root` as encrypted
+pub fn mark_encrypted(root: &Path, uuid: &str) {
+    for e in walkdir::WalkDir::new(root.join("commits").join(uuid)) {
+        let e = e.unwrap();
+        if e.file_type().is_file() {
+            let json = fs::read_to_string(e.path()).unwrap();
+            let json = json.replacen('{', r#"{"encrypted": "true", "#, 1);
+            fs::write(e.path(), json).unwrap();
+        }
+    }
 }
 
 pub const TR_BASIC: TestRepo = TestRepo {